@@ -1,35 +1,242 @@
-use crate::tast::N;
 use crate::ty::*;
+use crate::value::Value;
+use std::collections::HashMap;
 use std::fmt::{self, Display};
+use std::ops::Index;
+use std::sync::Arc;
 
 use once_cell::sync::Lazy;
 
-pub type BuiltinName = &'static str;
+/// Implementation of a builtin, called with exactly `arity` evaluated
+/// arguments. Errors are reported as runtime errors at the call site.
+///
+/// It's `Send + Sync` so that the standard builtins in `B` can be shared by
+/// all threads, even though the values it's called with stay on one thread.
+pub type BuiltinFn = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
 
-impl Display for N<BuiltinName> {
+pub struct Builtin {
+    pub name: String,
+    pub ty: Ty,
+    pub arity: usize,
+    pub func: BuiltinFn,
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builtin")
+            .field("name", &self.name)
+            .field("ty", &self.ty)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl Display for Builtin {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "BuiltinName {}: {}", self.t, self.ty)
+        write!(f, "Builtin {}: {}", self.name, self.ty)
     }
 }
 
-pub struct BuiltinDefs {
-    pub plus: N<BuiltinName>,
-    pub minus: N<BuiltinName>,
-    pub less: N<BuiltinName>,
+/// Registry of builtins, which are looked up by name.
+#[derive(Debug, Clone, Default)]
+pub struct Builtins {
+    defs: HashMap<String, Arc<Builtin>>,
 }
 
-pub static B: Lazy<BuiltinDefs> = Lazy::new(|| BuiltinDefs::new());
+/// The standard builtins every program can use.
+pub static B: Lazy<Builtins> = Lazy::new(Builtins::std);
 
-impl BuiltinDefs {
-    fn new() -> Self {
-        let cmp_type = Ty::mk_func_2(Ty::Int, Ty::Int, Ty::Bool);
-        let less = N::new("less", cmp_type.clone());
+impl Builtins {
+    fn std() -> Self {
+        let mut b = Builtins::default();
 
+        // Integer arithmetic is checked, overflow is an error rather than
+        // wrapping around.
         let arith_type = Ty::mk_func_2(Ty::Int, Ty::Int, Ty::Int);
+        b.register("plus", arith_type.clone(), |args| {
+            checked_2(args, "+", i64::checked_add)
+        });
+        b.register("minus", arith_type.clone(), |args| {
+            checked_2(args, "-", i64::checked_sub)
+        });
+        b.register("times", arith_type.clone(), |args| {
+            checked_2(args, "*", i64::checked_mul)
+        });
+        // Division truncates towards zero and the remainder has the sign of
+        // the dividend, as in Rust.
+        b.register("div", arith_type.clone(), |args| {
+            non_zero_divisor(args)?;
+            checked_2(args, "/", i64::checked_div)
+        });
+        b.register("mod", arith_type, |args| {
+            non_zero_divisor(args)?;
+            checked_2(args, "mod", i64::checked_rem)
+        });
+        b.register("neg", Ty::mk_func_1(Ty::Int, Ty::Int), |args| match args {
+            [Value::Int(n)] => n
+                .checked_neg()
+                .map(Value::Int)
+                .ok_or_else(|| format!("Integer overflow: -({})", n)),
+            _ => Err(format!("Cannot negate {}", show_args(args))),
+        });
+
+        let cmp_type = Ty::mk_func_2(Ty::Int, Ty::Int, Ty::Bool);
+        b.register("less", cmp_type.clone(), |args| {
+            int_2(args, "compare", |n1, n2| (n1 < n2).into())
+        });
+        b.register("less_eq", cmp_type.clone(), |args| {
+            int_2(args, "compare", |n1, n2| (n1 <= n2).into())
+        });
+        b.register("greater", cmp_type.clone(), |args| {
+            int_2(args, "compare", |n1, n2| (n1 > n2).into())
+        });
+        b.register("greater_eq", cmp_type, |args| {
+            int_2(args, "compare", |n1, n2| (n1 >= n2).into())
+        });
+
+        // Equality is defined for ints and bools. The type checker only lets
+        // the variable stand for types other than functions.
+        let eq_type = Ty::mk_func_2(Ty::Var(0), Ty::Var(0), Ty::Bool);
+        b.register("eq", eq_type, |args| match args {
+            [Value::Int(n1), Value::Int(n2)] => Ok((n1 == n2).into()),
+            [Value::Bool(b1), Value::Bool(b2)] => Ok((b1 == b2).into()),
+            _ => Err(format!("Cannot compare for equality {}", show_args(args))),
+        });
+
+        // Builtin types are closed, so their variables are implicitly
+        // quantified: `error : forall a. () -> a`.
+        b.register("error", Ty::mk_func_n(vec![], Ty::Var(0)), |_| {
+            Err("error() called".to_string())
+        });
+
+        b
+    }
+
+    /// Adds a builtin, replacing any builtin with the same name. Its arity is
+    /// taken from the function type, type variables are implicitly quantified.
+    ///
+    /// Panics if `ty` isn't a function type.
+    pub fn register<F>(&mut self, name: &str, ty: Ty, func: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        let arity = match &ty {
+            Ty::F { par, .. } => par.len(),
+            other => panic!("Builtin {} must be a function, not {}", name, other),
+        };
+        let builtin = Builtin {
+            name: name.to_string(),
+            ty,
+            arity,
+            func: Arc::new(func),
+        };
+        self.defs.insert(name.to_string(), Arc::new(builtin));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Builtin>> {
+        self.defs.get(name)
+    }
+}
+
+impl Index<&str> for Builtins {
+    type Output = Arc<Builtin>;
+
+    fn index(&self, name: &str) -> &Arc<Builtin> {
+        self.get(name)
+            .unwrap_or_else(|| panic!("Unknown builtin: {}", name))
+    }
+}
+
+fn int_2(args: &[Value], what: &str, f: fn(i64, i64) -> Value) -> Result<Value, String> {
+    match args {
+        [Value::Int(n1), Value::Int(n2)] => Ok(f(*n1, *n2)),
+        _ => Err(format!("Cannot {} {}", what, show_args(args))),
+    }
+}
+
+fn checked_2(args: &[Value], op: &str, f: fn(i64, i64) -> Option<i64>) -> Result<Value, String> {
+    match args {
+        [Value::Int(n1), Value::Int(n2)] => f(*n1, *n2)
+            .map(Value::Int)
+            .ok_or_else(|| format!("Integer overflow: {} {} {}", n1, op, n2)),
+        _ => Err(format!("Cannot apply {} to {}", op, show_args(args))),
+    }
+}
+
+fn non_zero_divisor(args: &[Value]) -> Result<(), String> {
+    match args {
+        [_, Value::Int(0)] => Err("Division by zero".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn show_args(args: &[Value]) -> String {
+    args.iter()
+        .map(|arg| format!("{}", arg))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn t_builtin_registry() {
+        assert_eq!(B["plus"].arity, 2);
+        assert_eq!(B["error"].arity, 0);
+        assert!(B.get("times").is_some());
+        assert!(B.get("pow").is_none());
+
+        let neg = &B["neg"].func;
+        assert!(matches!(neg(&[Value::Int(3)]), Ok(Value::Int(-3))));
+        assert_eq!(neg(&[Value::Bool(true)]).unwrap_err(), "Cannot negate True");
+    }
+
+    fn call(name: &str, args: &[Value]) -> Result<String, String> {
+        (B[name].func)(args).map(|v| format!("{}", v))
+    }
+
+    #[test]
+    fn t_builtin_arithmetic() {
+        use Value::Int;
+
+        assert_eq!(call("div", &[Int(-7), Int(2)]), Ok("-3".to_string()));
+        assert_eq!(call("mod", &[Int(-7), Int(2)]), Ok("-1".to_string()));
+        assert_eq!(
+            call("div", &[Int(1), Int(0)]),
+            Err("Division by zero".to_string())
+        );
+        assert_eq!(
+            call("mod", &[Int(1), Int(0)]),
+            Err("Division by zero".to_string())
+        );
+        assert_eq!(
+            call("times", &[Int(i64::MAX), Int(2)]),
+            Err(format!("Integer overflow: {} * 2", i64::MAX))
+        );
+        assert!(call("div", &[Int(i64::MIN), Int(-1)]).is_err());
+        assert!(call("neg", &[Int(i64::MIN)]).is_err());
+        assert!(call("plus", &[Int(i64::MAX), Int(1)]).is_err());
+    }
 
-        let plus = N::new("plus", arith_type.clone());
-        let minus = N::new("minus", arith_type.clone());
+    #[test]
+    fn t_builtin_comparisons() {
+        use Value::{Bool, Int};
 
-        BuiltinDefs { plus, minus, less }
+        assert_eq!(call("less_eq", &[Int(2), Int(2)]), Ok("True".to_string()));
+        assert_eq!(call("greater", &[Int(2), Int(2)]), Ok("False".to_string()));
+        assert_eq!(
+            call("greater_eq", &[Int(3), Int(2)]),
+            Ok("True".to_string())
+        );
+        assert_eq!(
+            call("eq", &[Bool(true), Bool(false)]),
+            Ok("False".to_string())
+        );
+        assert_eq!(call("eq", &[Int(1), Int(1)]), Ok("True".to_string()));
+        let eq = Value::Builtin(B["eq"].clone());
+        assert!(call("eq", &[eq.clone(), eq]).is_err());
     }
 }
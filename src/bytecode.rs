@@ -0,0 +1,358 @@
+use crate::interp::RuntimeError;
+use crate::parser::SrcSpan;
+use crate::tast::{Binding, Condition, Ex, Lambda, NameDef, N};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A VM instruction. Operands are indices, of a slot in the current frame, a
+/// captured value, a global, an entry of the prototype's pools, or of an
+/// instruction for jumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Const(u32),
+    Local(u32),
+    /// Pops a value into a slot.
+    SetLocal(u32),
+    Captured(u32),
+    Global(u32),
+    /// Pushes the running closure, which is how functions refer to themselves.
+    This,
+    /// Makes a closure of a nested prototype.
+    Closure(u32),
+    /// Calls the function below this many arguments on the stack.
+    Call(u32),
+    /// Like `Call`, but the callee takes over the frame of the caller when
+    /// possible. Always followed by a `Return` for when it isn't.
+    TailCall(u32),
+    Jump(u32),
+    /// Pops a boolean and jumps if it's false.
+    JumpIfFalse(u32),
+    Return,
+}
+
+/// Where a new closure takes each of its captured values from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    Local(u32),
+    Captured(u32),
+    This,
+}
+
+/// Compiled code of a lambda, or of a top-level item, which takes no
+/// arguments.
+#[derive(Debug)]
+pub struct Proto {
+    pub name: Option<NameDef>,
+    pub arity: usize,
+    /// Size of the frame: arguments come first, then the names bound by lets.
+    pub slots: usize,
+    pub code: Vec<Op>,
+    /// Where each instruction comes from, for runtime errors.
+    pub spans: Vec<Option<SrcSpan>>,
+    pub consts: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+    pub captures: Vec<Capture>,
+}
+
+type Result<A> = std::result::Result<A, RuntimeError>;
+
+/// Compiles top-level items one at a time. Names defined at the top level
+/// become globals, which are numbered in the order of definition.
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    globals: HashMap<NameDef, u32>,
+    defined: u32,
+}
+
+impl Compiler {
+    /// Compiles a top-level item into code that returns its value. Defining
+    /// the name of a binding is left to the caller, once the value is known.
+    pub fn compile_top(&self, ex: &Ex) -> Result<Rc<Proto>> {
+        let mut top = FnBuilder::new(&self.globals, None, &[], vec![]);
+        match ex {
+            Ex::Bind(b) => top.binding(b)?,
+            other => top.ex(other, false)?,
+        }
+        top.emit(Op::Return, None);
+        Ok(top.finish())
+    }
+
+    /// Makes `name` refer to the next global.
+    pub fn define(&mut self, name: &NameDef) -> u32 {
+        let global = self.defined;
+        self.globals.insert(name.clone(), global);
+        self.defined += 1;
+        global
+    }
+}
+
+/// Where a name is stored in the code being compiled.
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    Local(u32),
+    Captured(u32),
+    This,
+    Global(u32),
+}
+
+struct FnBuilder<'a> {
+    globals: &'a HashMap<NameDef, u32>,
+    proto: Proto,
+    /// Names of the slots in scope, innermost last.
+    locals: Vec<NameDef>,
+    captured: Vec<NameDef>,
+}
+
+impl<'a> FnBuilder<'a> {
+    fn new(
+        globals: &'a HashMap<NameDef, u32>,
+        name: Option<NameDef>,
+        bound: &[N<NameDef>],
+        captured: Vec<NameDef>,
+    ) -> FnBuilder<'a> {
+        let locals: Vec<_> = bound.iter().map(|n| n.t.as_ref().clone()).collect();
+        FnBuilder {
+            globals,
+            proto: Proto {
+                name,
+                arity: locals.len(),
+                slots: locals.len(),
+                code: vec![],
+                spans: vec![],
+                consts: vec![],
+                protos: vec![],
+                captures: vec![],
+            },
+            locals,
+            captured,
+        }
+    }
+
+    fn finish(self) -> Rc<Proto> {
+        Rc::new(self.proto)
+    }
+
+    /// Scoping follows the interpreter: arguments and lets shadow the name of
+    /// the function, which shadows the captured names.
+    fn place(&self, name: &NameDef) -> Option<Place> {
+        if let Some(slot) = self.locals.iter().rposition(|n| n == name) {
+            return Some(Place::Local(slot as u32));
+        }
+        if self.proto.name.as_ref() == Some(name) {
+            return Some(Place::This);
+        }
+        if let Some(idx) = self.captured.iter().position(|n| n == name) {
+            return Some(Place::Captured(idx as u32));
+        }
+        self.globals.get(name).map(|&g| Place::Global(g))
+    }
+
+    fn emit(&mut self, op: Op, span: Option<SrcSpan>) -> usize {
+        self.proto.code.push(op);
+        self.proto.spans.push(span);
+        self.proto.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.proto.code.len() as u32;
+        match &mut self.proto.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            other => unreachable!("Only jumps are patched: {:?}", other),
+        }
+    }
+
+    fn constant(&mut self, val: Value) -> u32 {
+        let existing = self.proto.consts.iter().position(|c| match (c, &val) {
+            (Value::Int(n1), Value::Int(n2)) => n1 == n2,
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            (Value::Builtin(b1), Value::Builtin(b2)) => Arc::ptr_eq(b1, b2),
+            _ => false,
+        });
+        existing.unwrap_or_else(|| {
+            self.proto.consts.push(val);
+            self.proto.consts.len() - 1
+        }) as u32
+    }
+
+    /// Compiles code that pushes the value of the expression, or returns it
+    /// if the expression is in tail position.
+    fn ex(&mut self, ex: &Ex, tail: bool) -> Result<()> {
+        match ex {
+            Ex::Bind(b) => match &b.t.ex {
+                Ex::Lam(l) => self.lambda(l, Some(&b.t.name))?,
+                other => return self.ex(other, tail),
+            },
+            Ex::Let(l) => {
+                let scope = self.locals.len();
+                for b in &l.t.bindings {
+                    self.binding(b)?;
+                    let slot = self.locals.len();
+                    self.emit(Op::SetLocal(slot as u32), b.span);
+                    self.locals.push(b.t.name.t.as_ref().clone());
+                    self.proto.slots = self.proto.slots.max(self.locals.len());
+                }
+                self.ex(&l.t.body, tail)?;
+                self.locals.truncate(scope);
+                return Ok(());
+            }
+            Ex::Lam(l) => self.lambda(l, None)?,
+            Ex::Ap(a) => {
+                self.ex(&a.t.ex, false)?;
+                for arg in &a.t.args {
+                    self.ex(arg, false)?;
+                }
+                let argc = a.t.args.len() as u32;
+                if tail {
+                    self.emit(Op::TailCall(argc), a.span);
+                    self.emit(Op::Return, a.span);
+                    return Ok(());
+                }
+                self.emit(Op::Call(argc), a.span);
+            }
+            Ex::Cond(c) => {
+                let Condition { pred, then, els } = c.t.as_ref();
+                self.ex(pred, false)?;
+                let to_els = self.emit(Op::JumpIfFalse(0), pred.span());
+                self.ex(then, tail)?;
+                if tail {
+                    self.patch(to_els);
+                    return self.ex(els, true);
+                }
+                let to_end = self.emit(Op::Jump(0), None);
+                self.patch(to_els);
+                self.ex(els, false)?;
+                self.patch(to_end);
+            }
+            Ex::URef(r) => {
+                let op = match self.place(&r.t.name) {
+                    Some(Place::Local(slot)) => Op::Local(slot),
+                    Some(Place::Captured(idx)) => Op::Captured(idx),
+                    Some(Place::This) => Op::This,
+                    Some(Place::Global(g)) => Op::Global(g),
+                    None => return Err(unknown_name(&r.t.name, r.span)),
+                };
+                self.emit(op, r.span);
+            }
+            Ex::BRef(b) => {
+                let idx = self.constant(Value::Builtin(b.clone()));
+                self.emit(Op::Const(idx), None);
+            }
+            Ex::ConstInt(n) => {
+                let idx = self.constant(Value::Int(*n.t));
+                self.emit(Op::Const(idx), n.span);
+            }
+            Ex::ConstBool(b) => {
+                let idx = self.constant(Value::Bool(*b.t));
+                self.emit(Op::Const(idx), b.span);
+            }
+        }
+        if tail {
+            self.emit(Op::Return, ex.span());
+        }
+        Ok(())
+    }
+
+    /// Pushes the value of the right-hand side of a binding.
+    fn binding(&mut self, b: &N<Binding>) -> Result<()> {
+        match &b.t.ex {
+            Ex::Lam(l) => self.lambda(l, Some(&b.t.name)),
+            other => self.ex(other, false),
+        }
+    }
+
+    /// Compiles the lambda into a nested prototype and pushes a closure of it.
+    /// Globals aren't captured, the lambda refers to them directly.
+    fn lambda(&mut self, lam: &N<Lambda>, name: Option<&N<NameDef>>) -> Result<()> {
+        let name = name.map(|n| n.t.as_ref().clone());
+        let mut captures = vec![];
+        let mut captured = vec![];
+        for free in &lam.free {
+            if name.as_ref() == Some(&free.t) {
+                continue;
+            }
+            let capture = match self.place(&free.t) {
+                Some(Place::Local(slot)) => Capture::Local(slot),
+                Some(Place::Captured(idx)) => Capture::Captured(idx),
+                Some(Place::This) => Capture::This,
+                Some(Place::Global(_)) => continue,
+                None => return Err(unknown_name(&free.t, free.span)),
+            };
+            captures.push(capture);
+            captured.push(free.t.as_ref().clone());
+        }
+
+        let mut inner = FnBuilder::new(self.globals, name, &lam.bound, captured);
+        inner.proto.captures = captures;
+        inner.ex(&lam.body, true)?;
+        self.proto.protos.push(inner.finish());
+        let idx = self.proto.protos.len() - 1;
+        self.emit(Op::Closure(idx as u32), lam.span);
+        Ok(())
+    }
+}
+
+fn unknown_name(name: &NameDef, span: Option<SrcSpan>) -> RuntimeError {
+    RuntimeError::new(format!("Unknown name: {}", name), span)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lower::lower_unit;
+    use crate::parser::parse;
+    use crate::resolve::resolve_unit;
+    use pretty_assertions::assert_eq;
+
+    fn compile_last(code: &str) -> Rc<Proto> {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        let exs = lower_unit(&unit, &res).unwrap();
+        Compiler::default()
+            .compile_top(exs.last().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn t_compile_slots_and_constants() {
+        let top = compile_last(r"let x = 1, y = 1 in \z -> x + z");
+        assert_eq!(top.slots, 2);
+        assert_eq!(top.consts.len(), 1);
+        assert_eq!(
+            top.code,
+            vec![
+                Op::Const(0),
+                Op::SetLocal(0),
+                Op::Const(0),
+                Op::SetLocal(1),
+                Op::Closure(0),
+                Op::Return
+            ]
+        );
+
+        let lam = &top.protos[0];
+        assert_eq!(lam.captures, vec![Capture::Local(0)]);
+        assert_eq!(
+            lam.code,
+            vec![
+                Op::Const(0),
+                Op::Captured(0),
+                Op::Local(0),
+                Op::TailCall(2),
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn t_compile_self_reference() {
+        let top = compile_last("f n = if n < 1 then 0 else f(n - 1)");
+        let f = &top.protos[0];
+        assert_eq!(f.name.as_ref().map(|n| n.as_str()), Some("f"));
+        assert!(f.captures.is_empty());
+        assert!(f.code.contains(&Op::This));
+        assert!(f.code.contains(&Op::TailCall(1)));
+    }
+}
@@ -0,0 +1,606 @@
+use crate::parser::{Loc, SourceId, Sources, SrcSpan, WithCode};
+use serde_json::json;
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A part of the code a diagnostic points at, with a message that may be
+/// empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: SrcSpan,
+    pub msg: String,
+}
+
+impl Label {
+    pub fn new(span: SrcSpan, msg: impl Into<String>) -> Label {
+        Label {
+            span,
+            msg: msg.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteKind {
+    Note,
+    Help,
+}
+
+/// Text printed after the code, in order, with its own snippet if it has a
+/// span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub kind: NoteKind,
+    pub msg: String,
+    pub span: Option<SrcSpan>,
+}
+
+/// A problem found in the code, rendered against it with `WithCode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Which kind of problem this is, stable for tools to match on.
+    pub code: Option<&'static str>,
+    pub msg: String,
+    /// Where the problem is, `None` if it isn't tied to a part of the code.
+    pub primary: Option<Label>,
+    /// Other parts of the code that explain the problem.
+    pub secondary: Vec<Label>,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, msg: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code: None,
+            msg: msg.into(),
+            primary: None,
+            secondary: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn error(msg: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, msg)
+    }
+
+    pub fn with_code(self, code: &'static str) -> Diagnostic {
+        Diagnostic {
+            code: Some(code),
+            ..self
+        }
+    }
+
+    /// Points at `span`, without a message of its own, if it's known.
+    pub fn at(self, span: Option<SrcSpan>) -> Diagnostic {
+        Diagnostic {
+            primary: span.map(|span| Label::new(span, "")),
+            ..self
+        }
+    }
+
+    pub fn with_label(mut self, label: Label) -> Diagnostic {
+        self.secondary.push(label);
+        self
+    }
+
+    pub fn with_note(mut self, msg: impl Into<String>, span: Option<SrcSpan>) -> Diagnostic {
+        self.notes.push(Note {
+            kind: NoteKind::Note,
+            msg: msg.into(),
+            span,
+        });
+        self
+    }
+
+    pub fn with_help(mut self, msg: impl Into<String>) -> Diagnostic {
+        self.notes.push(Note {
+            kind: NoteKind::Help,
+            msg: msg.into(),
+            span: None,
+        });
+        self
+    }
+
+    /// The diagnostic as a JSON object. Lines and columns are one-based, like
+    /// in the rendered text, and columns count bytes.
+    pub fn to_json(&self) -> serde_json::Value {
+        let label = |l: &Label| {
+            json!({
+                "message": l.msg,
+                "span": span_json(l.span),
+            })
+        };
+        let note = |n: &Note| {
+            json!({
+                "kind": n.kind.to_string(),
+                "message": n.msg,
+                "span": n.span.map(span_json),
+            })
+        };
+        json!({
+            "message": self.msg,
+            "severity": self.severity.to_string(),
+            "code": self.code,
+            "span": self.primary.as_ref().map(|l| span_json(l.span)),
+            "labels": self.secondary.iter().map(label).collect::<Vec<_>>(),
+            "notes": self.notes.iter().map(note).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn span_json(span: SrcSpan) -> serde_json::Value {
+    let (offset, loc) = (span.offset, span.loc);
+    json!({
+        "byte_start": offset.start,
+        "byte_end": offset.end,
+        "line_start": loc.start.row + 1,
+        "column_start": loc.start.col + 1,
+        "line_end": loc.end.row + 1,
+        "column_end": loc.end.col + 1,
+    })
+}
+
+/// Spans with more lines than this show only their first and last lines.
+const MAX_SPAN_LINES: usize = 4;
+
+impl Display for NoteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoteKind::Note => write!(f, "note"),
+            NoteKind::Help => write!(f, "help"),
+        }
+    }
+}
+
+impl<'a, 'b> Display for WithCode<'a, 'b, Diagnostic> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        render(f, self.t, &|_| Some(self.code))
+    }
+}
+
+/// A diagnostic whose spans can be in different sources, rendered against
+/// the code of each span's source. Spans of unknown sources show only their
+/// location.
+pub struct WithSources<'a, 'b> {
+    sources: &'a Sources,
+    d: &'b Diagnostic,
+}
+
+impl<'a, 'b> WithSources<'a, 'b> {
+    pub fn new(sources: &'a Sources, d: &'b Diagnostic) -> WithSources<'a, 'b> {
+        WithSources { sources, d }
+    }
+}
+
+impl<'a, 'b> Display for WithSources<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        render(f, self.d, &|source| self.sources.get(source))
+    }
+}
+
+fn render<'c>(
+    f: &mut fmt::Formatter<'_>,
+    d: &Diagnostic,
+    code_of: &dyn Fn(SourceId) -> Option<&'c str>,
+) -> fmt::Result {
+    let spans = d.primary.iter().chain(&d.secondary).map(|l| l.span);
+    let row_width = spans
+        .chain(d.notes.iter().filter_map(|n| n.span))
+        .map(|span| (span.loc.end.row + 1).to_string().len())
+        .max()
+        .unwrap_or(0);
+    let snippet = |span: Option<SrcSpan>| {
+        let source = span.map_or(SourceId::default(), |s| s.source());
+        Snippet {
+            source,
+            lines: span.and(code_of(source)).map(Lines::new),
+            gutter: " ".repeat(row_width),
+        }
+    };
+
+    writeln!(f, "{}: {}", d.severity, d.msg)?;
+    // The labels are shown in the code of the first one. Those that don't fit
+    // it, e.g. of other code, aren't shown.
+    let labels: Vec<_> = d
+        .primary
+        .iter()
+        .map(|l| (l, true))
+        .chain(d.secondary.iter().map(|l| (l, false)))
+        .collect();
+    let main = snippet(labels.first().map(|(l, _)| l.span));
+    main.write(f, d.primary.as_ref().map(|l| l.span), &labels)?;
+
+    for note in &d.notes {
+        match note.span {
+            None => writeln!(f, "{} = {}: {}", main.gutter, note.kind, note.msg)?,
+            Some(span) => {
+                writeln!(f, "{}: {}", note.kind, note.msg)?;
+                let label = Label::new(span, "");
+                snippet(Some(span)).write(f, Some(span), &[(&label, false)])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Snippet<'c> {
+    source: SourceId,
+    /// The code of the source, `None` if it's unknown.
+    lines: Option<Lines<'c>>,
+    /// Blank space as wide as the line numbers.
+    gutter: String,
+}
+
+impl<'c> Snippet<'c> {
+    fn line(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        lines: &Lines<'c>,
+        row: usize,
+        prefix: &str,
+    ) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>w$} | {}{}",
+            row + 1,
+            prefix,
+            lines.text(row),
+            w = self.gutter.len()
+        )
+    }
+
+    fn annotation(&self, f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+        writeln!(f, "{} | {}", self.gutter, text.trim_end())
+    }
+
+    /// Prints the lines of the marks with the marks under them, after the
+    /// location of the diagnostic.
+    fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        location: Option<SrcSpan>,
+        labels: &[(&Label, bool)],
+    ) -> fmt::Result {
+        if let Some(span) = location {
+            let start = span.loc.start;
+            writeln!(f, "{}--> {}:{}", self.gutter, start.row + 1, start.col + 1)?;
+        }
+        let lines = match &self.lines {
+            Some(lines) => lines,
+            None => return Ok(()),
+        };
+        let mut marks: Vec<_> = labels
+            .iter()
+            .filter(|(l, _)| l.span.source() == self.source)
+            .filter_map(|(l, primary)| lines.mark(l, *primary))
+            .collect();
+        if marks.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "{} |", self.gutter)?;
+        marks.sort_by_key(|m| m.start);
+
+        // Marks on the same line share the printed line.
+        let mut last_row: Option<usize> = None;
+        let mut idx = 0;
+        while idx < marks.len() {
+            let first = &marks[idx];
+            let (row, col) = first.start;
+            if last_row.is_some_and(|last| row > last + 1) {
+                writeln!(f, "...")?;
+            }
+
+            if first.is_multiline() {
+                let (end_row, end_col) = first.end;
+                // Spans starting at the indentation are marked next to the line.
+                if col <= lines.indent(row) {
+                    self.line(f, lines, row, "/ ")?;
+                } else {
+                    self.line(f, lines, row, "  ")?;
+                    self.annotation(f, &format!(" {}{}", "_".repeat(col + 1), first.hat()))?;
+                }
+                let elide = end_row - row + 1 > MAX_SPAN_LINES;
+                for mid in row + 1..=end_row {
+                    if elide && mid == row + 2 {
+                        writeln!(f, "...")?;
+                    }
+                    if !elide || mid < row + 2 || mid == end_row {
+                        self.line(f, lines, mid, "| ")?;
+                    }
+                }
+                let underline = format!("|{}{}", "_".repeat(end_col + 1), first.hat());
+                self.annotation(f, &format!("{} {}", underline, first.msg))?;
+                last_row = Some(end_row);
+                idx += 1;
+                continue;
+            }
+
+            self.line(f, lines, row, "")?;
+            while idx < marks.len() && marks[idx].start.0 == row && !marks[idx].is_multiline() {
+                let mark = &marks[idx];
+                let underline = mark.hat().repeat(mark.end.1 - mark.start.1 + 1);
+                let indent = " ".repeat(mark.start.1);
+                self.annotation(f, &format!("{}{} {}", indent, underline, mark.msg))?;
+                idx += 1;
+            }
+            last_row = Some(row);
+        }
+        Ok(())
+    }
+}
+
+/// A label located on the lines of the code, with columns in printed
+/// characters. `end` is the position of the last marked character.
+struct Mark<'d> {
+    start: (usize, usize),
+    end: (usize, usize),
+    primary: bool,
+    msg: &'d str,
+}
+
+impl<'d> Mark<'d> {
+    fn is_multiline(&self) -> bool {
+        self.start.0 != self.end.0
+    }
+
+    fn hat(&self) -> &'static str {
+        if self.primary {
+            "^"
+        } else {
+            "-"
+        }
+    }
+}
+
+/// Tabs are printed as this many spaces.
+const TAB_WIDTH: usize = 4;
+
+struct Lines<'c> {
+    code: &'c str,
+    starts: Vec<usize>,
+}
+
+impl<'c> Lines<'c> {
+    fn new(code: &'c str) -> Lines<'c> {
+        let starts = std::iter::once(0)
+            .chain(code.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Lines { code, starts }
+    }
+
+    fn raw(&self, row: usize) -> &'c str {
+        let start = self.starts[row];
+        let end = self.starts.get(row + 1).map_or(self.code.len(), |&s| s - 1);
+        self.code[start..end].trim_end_matches('\r')
+    }
+
+    fn text(&self, row: usize) -> String {
+        self.raw(row).replace('\t', &" ".repeat(TAB_WIDTH))
+    }
+
+    /// Printed width of the line's leading whitespace.
+    fn indent(&self, row: usize) -> usize {
+        let raw = self.raw(row);
+        width(&raw[..raw.len() - raw.trim_start().len()])
+    }
+
+    /// Row and printed column of a byte offset.
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.code.len());
+        let row = self.starts.partition_point(|&s| s <= offset) - 1;
+        let raw = self.raw(row);
+        let col_byte = (offset - self.starts[row]).min(raw.len());
+        (row, width(&raw[..col_byte]))
+    }
+
+    /// Whether the span is of this code: its offsets are in bounds, on char
+    /// boundaries, and at its row and column.
+    fn fits(&self, span: SrcSpan) -> bool {
+        let (offset, loc) = (span.offset, span.loc);
+        let at = |offset: usize, loc: Loc| {
+            self.code.is_char_boundary(offset)
+                && self.starts.get(loc.row).map(|s| s + loc.col) == Some(offset)
+        };
+        offset.start <= offset.end
+            && offset.end <= self.code.len()
+            && at(offset.start, loc.start)
+            && at(offset.end, loc.end)
+    }
+
+    fn mark<'d>(&self, label: &'d Label, primary: bool) -> Option<Mark<'d>> {
+        if !self.fits(label.span) {
+            return None;
+        }
+        let offset = label.span.offset;
+        let start = self.position(offset.start);
+        // Empty spans, e.g. of missing tokens, mark the character after them,
+        // and newlines at the end of a span aren't marked.
+        let marked = self.code[offset.start..offset.end].trim_end_matches(['\n', '\r']);
+        let last = marked
+            .char_indices()
+            .next_back()
+            .map_or(offset.start, |(i, _)| offset.start + i);
+        let end = self.position(last);
+        Some(Mark {
+            start,
+            end,
+            primary,
+            msg: &label.msg,
+        })
+    }
+}
+
+fn width(s: &str) -> usize {
+    s.chars()
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Span;
+    use pretty_assertions::assert_eq;
+
+    fn span(code: &str, start: usize, end: usize) -> SrcSpan {
+        let loc = |offset: usize| {
+            let before = &code[..offset];
+            Loc {
+                row: before.matches('\n').count(),
+                col: offset - before.rfind('\n').map_or(0, |nl| nl + 1),
+            }
+        };
+        SrcSpan {
+            offset: Span { start, end },
+            loc: Span {
+                start: loc(start),
+                end: loc(end),
+            },
+            source: SourceId::default(),
+        }
+    }
+
+    fn render(code: &str, d: &Diagnostic) -> String {
+        format!("{}", WithCode::new(code, d))
+    }
+
+    #[test]
+    fn t_render_labels() {
+        let code = "let a = 1,\n    b = f(1)\nin\n    a + b";
+        let f = code.find("f(1)").unwrap();
+        let d = Diagnostic::error("Cannot apply a non-function")
+            .at(Some(span(code, f, f + 4)))
+            .with_label(Label::new(span(code, 0, 3), "in this `let`"))
+            .with_label(Label::new(span(code, f + 2, f + 3), "argument"))
+            .with_help("Define `f` first");
+        assert_eq!(
+            render(code, &d),
+            "\
+error: Cannot apply a non-function
+ --> 2:9
+  |
+1 | let a = 1,
+  | --- in this `let`
+2 |     b = f(1)
+  |         ^^^^
+  |           - argument
+  = help: Define `f` first
+"
+        );
+    }
+
+    #[test]
+    fn t_render_foreign_spans() {
+        let old = "fdef x = if x < 1 then error() else fdef(x - 1)";
+        let d = Diagnostic::error("error() called")
+            .at(Some(span(old, 23, 30)))
+            .with_note("in fdef, called here", Some(span(old, 36, 47)));
+        assert_eq!(
+            render("fdef(3)", &d),
+            "\
+error: error() called
+ --> 1:24
+note: in fdef, called here
+ --> 1:37
+"
+        );
+
+        // Offsets in bounds, but of a different line.
+        let d = Diagnostic::error("Stale").at(Some(span("a\nb = 1", 2, 3)));
+        assert_eq!(render("ab = 1", &d), "error: Stale\n --> 2:1\n");
+    }
+
+    #[test]
+    fn t_diagnostic_json() {
+        let code = "x = 1\ny = x + True";
+        let d = Diagnostic::error("expected Int, found Bool")
+            .with_code("type")
+            .at(Some(span(code, 14, 18)))
+            .with_label(Label::new(span(code, 0, 1), "defined here"))
+            .with_help("Use an Int");
+        assert_eq!(
+            d.to_json(),
+            json!({
+                "message": "expected Int, found Bool",
+                "severity": "error",
+                "code": "type",
+                "span": {
+                    "byte_start": 14,
+                    "byte_end": 18,
+                    "line_start": 2,
+                    "column_start": 9,
+                    "line_end": 2,
+                    "column_end": 13,
+                },
+                "labels": [{
+                    "message": "defined here",
+                    "span": {
+                        "byte_start": 0,
+                        "byte_end": 1,
+                        "line_start": 1,
+                        "column_start": 1,
+                        "line_end": 1,
+                        "column_end": 2,
+                    },
+                }],
+                "notes": [{ "kind": "help", "message": "Use an Int", "span": null }],
+            })
+        );
+    }
+
+    #[test]
+    fn t_render_multiline_and_tabs() {
+        let code = "x = 1\nf n =\n\tif n\n\tthen 1\n\telse 2";
+        let start = code.find("n\n\tthen").unwrap();
+        let d = Diagnostic::error("Mismatched types").at(Some(span(code, start, code.len())));
+        assert_eq!(
+            render(code, &d),
+            "\
+error: Mismatched types
+ --> 3:5
+  |
+3 |       if n
+  |  ________^
+4 | |     then 1
+5 | |     else 2
+  | |__________^
+"
+        );
+
+        let code = "a\n  if b\n  then c\n  else d\n  more e\n  last f\n";
+        let start = code.find("if").unwrap();
+        let d = Diagnostic::error("Unexpected token")
+            .at(Some(span(code, start, code.len())))
+            .with_note("Lines in between are hidden", None);
+        assert_eq!(
+            render(code, &d),
+            "\
+error: Unexpected token
+ --> 2:3
+  |
+2 | /   if b
+3 | |   then c
+...
+6 | |   last f
+  | |________^
+  = note: Lines in between are hidden
+"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::builtin::{Builtins, B};
+use crate::diagnostic::Diagnostic;
+use crate::interp::{Interp, RuntimeError};
+use crate::limits::{Budget, Limits};
+use crate::parser::{self, ParsingError, Sources, WithCode};
+use crate::tast::{Ex, NameDef};
+use crate::ty::Ty;
+use crate::typeck::{TypeError, Typeck};
+use crate::value::Value;
+use crate::{lower, resolve};
+use std::fmt::{self, Display};
+
+/// Rust types that have a fang counterpart.
+pub trait FangType {
+    fn fang_ty() -> Ty;
+}
+
+pub trait IntoValue: FangType {
+    fn into_value(self) -> Value;
+}
+
+pub trait FromValue: FangType + Sized {
+    /// `None` if the value has a different type.
+    fn from_value(val: &Value) -> Option<Self>;
+}
+
+impl FangType for i64 {
+    fn fang_ty() -> Ty {
+        Ty::Int
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(val: &Value) -> Option<Self> {
+        match val {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FangType for bool {
+    fn fang_ty() -> Ty {
+        Ty::Bool
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(val: &Value) -> Option<Self> {
+        match val {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+/// Errors of the first compilation phase that failed, or of the evaluation.
+#[derive(Debug)]
+pub enum Error {
+    Parse(Vec<ParsingError>),
+    Type(Vec<TypeError>),
+    Runtime(RuntimeError),
+    /// A native function couldn't be registered.
+    Register(String),
+}
+
+impl Error {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Error::Parse(errors) => errors.iter().map(ParsingError::diagnostic).collect(),
+            Error::Type(errors) => errors.iter().map(TypeError::diagnostic).collect(),
+            Error::Runtime(e) => vec![e.diagnostic()],
+            Error::Register(msg) => {
+                vec![Diagnostic::error(format!("Registration error: {}", msg)).with_code("register")]
+            }
+        }
+    }
+}
+
+impl<'a, 'b> Display for WithCode<'a, 'b, Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.t
+            .diagnostics()
+            .iter()
+            .try_for_each(|d| write!(f, "{}", WithCode::new(self.code, d)))
+    }
+}
+
+/// What a top-level item of the evaluated code produced.
+#[derive(Debug)]
+pub enum Item {
+    Def(String, Ty),
+    Value(Value),
+}
+
+/// Evaluates fang code for a host program. Definitions made by the code are
+/// visible to the code evaluated after it, and the host can provide its own
+/// functions next to the standard builtins.
+///
+/// Values share their data through `Rc`, so an engine stays on the thread that
+/// made it. Threads that evaluate code make an engine each.
+#[derive(Clone)]
+pub struct Engine {
+    builtins: Builtins,
+    /// Top-level names defined so far, in the order of definition.
+    globals: Vec<NameDef>,
+    typeck: Typeck,
+    interp: Interp,
+    /// The code of every run, which the spans of runtime errors can point
+    /// into long after it ran.
+    sources: Sources,
+    limits: Limits,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine {
+            builtins: B.clone(),
+            globals: vec![],
+            typeck: Typeck::new(),
+            interp: Interp::new(),
+            sources: Sources::new(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Limits every following call of `eval` or `run`. The budget is shared by
+    /// all the top-level items of the code.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Makes a native function available under `name`. The code is type
+    /// checked against `ty`, so `func` is only called with matching values.
+    ///
+    /// Fails if `ty` isn't a function type or if `name` is already a builtin,
+    /// standard or registered before.
+    pub fn register<F>(&mut self, name: &str, ty: Ty, func: F) -> Result<(), Error>
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        if self.builtins.get(name).is_some() {
+            return Err(Error::Register(format!("{} is already defined", name)));
+        }
+        if !matches!(ty, Ty::F { .. }) {
+            let msg = format!("{} must be a function, not {}", name, ty);
+            return Err(Error::Register(msg));
+        }
+        self.builtins.register(name, ty, func);
+        Ok(())
+    }
+
+    /// Registers a function of one argument with the type derived from its
+    /// Rust signature.
+    pub fn register_fn1<A, R, F>(&mut self, name: &str, func: F) -> Result<(), Error>
+    where
+        A: FromValue,
+        R: IntoValue,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        let ty = Ty::mk_func_1(A::fang_ty(), R::fang_ty());
+        self.register(name, ty, move |args| {
+            let a = from_arg(&args[0])?;
+            Ok(func(a).into_value())
+        })
+    }
+
+    /// Registers a function of two arguments with the type derived from its
+    /// Rust signature.
+    pub fn register_fn2<A, B, R, F>(&mut self, name: &str, func: F) -> Result<(), Error>
+    where
+        A: FromValue,
+        B: FromValue,
+        R: IntoValue,
+        F: Fn(A, B) -> R + Send + Sync + 'static,
+    {
+        let ty = Ty::mk_func_2(A::fang_ty(), B::fang_ty(), R::fang_ty());
+        self.register(name, ty, move |args| {
+            let a = from_arg(&args[0])?;
+            let b = from_arg(&args[1])?;
+            Ok(func(a, b).into_value())
+        })
+    }
+
+    /// Evaluates the code and returns the value of its last top-level
+    /// expression, `None` if it ends with a definition.
+    pub fn eval(&mut self, code: &str) -> Result<Option<Value>, Error> {
+        let items = self.run(code)?;
+        match items.into_iter().last() {
+            Some(Item::Value(val)) => Ok(Some(val)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Evaluates every top-level item of the code. Its definitions are kept
+    /// only if the whole code succeeds. The spans of runtime errors are in the
+    /// code's source in `sources`.
+    pub fn run(&mut self, code: &str) -> Result<Vec<Item>, Error> {
+        let mut typeck = self.typeck.clone();
+        let exs = self.check_with(code, &mut typeck)?;
+        let source = self.sources.add(code);
+        let exs: Vec<_> = exs.iter().map(|ex| ex.in_source(source)).collect();
+
+        let mut interp = self.interp.clone();
+        let mut budget = Budget::new(self.limits);
+        let mut items = vec![];
+        for ex in &exs {
+            let item = match interp
+                .eval_top_in(ex, &mut budget)
+                .map_err(Error::Runtime)?
+            {
+                Some(val) => Item::Value(val),
+                None => match ex {
+                    Ex::Bind(b) => Item::Def(b.name.t.to_string(), b.ex.ty().clone()),
+                    other => unreachable!("Only bindings have no value: {}", other),
+                },
+            };
+            items.push(item);
+        }
+
+        for ex in &exs {
+            if let Ex::Bind(b) = ex {
+                self.globals.push(b.t.name.t.as_ref().clone());
+            }
+        }
+        self.typeck = typeck;
+        self.interp = interp;
+        Ok(items)
+    }
+
+    /// Type checks the code without evaluating it or keeping its definitions.
+    pub fn check(&self, code: &str) -> Result<Vec<Ex>, Error> {
+        self.check_with(code, &mut self.typeck.clone())
+    }
+
+    /// The code of the runs so far.
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    fn check_with(&self, code: &str, typeck: &mut Typeck) -> Result<Vec<Ex>, Error> {
+        let ast = parser::parse(code).map_err(Error::Parse)?;
+        let res =
+            resolve::resolve_unit_in(&ast, &self.globals, &self.builtins).map_err(Error::Parse)?;
+        let exs = lower::lower_unit_in(&ast, &res, &self.globals).map_err(Error::Parse)?;
+
+        let mut typed = vec![];
+        let mut errors = vec![];
+        for ex in &exs {
+            match typeck.check_top(ex) {
+                Ok(ex) => typed.push(ex),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(typed)
+        } else {
+            Err(Error::Type(errors))
+        }
+    }
+}
+
+fn from_arg<A: FromValue>(val: &Value) -> Result<A, String> {
+    A::from_value(val).ok_or_else(|| format!("Expected {}, found {}", A::fang_ty(), val))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limits::Limit;
+    use pretty_assertions::assert_eq;
+
+    fn eval_int(engine: &mut Engine, code: &str) -> i64 {
+        let val = engine.eval(code).unwrap().unwrap();
+        i64::from_value(&val).unwrap()
+    }
+
+    #[test]
+    fn t_engine_native_functions() {
+        let mut engine = Engine::new();
+        engine.register_fn1("price", |sku: i64| sku * 100).unwrap();
+        engine
+            .register_fn2(
+                "discounted",
+                |price: i64, vip: bool| {
+                    if vip {
+                        price / 2
+                    } else {
+                        price
+                    }
+                },
+            )
+            .unwrap();
+        engine
+            .register(
+                "sum3",
+                Ty::mk_func_n(vec![Ty::Int, Ty::Int, Ty::Int], Ty::Int),
+                |args| match args {
+                    [Value::Int(a), Value::Int(b), Value::Int(c)] => Ok(Value::Int(a + b + c)),
+                    _ => Err("Expected ints".to_string()),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(eval_int(&mut engine, "discounted(price(3), True)"), 150);
+        assert_eq!(eval_int(&mut engine, "sum3(1, 2, price(1))"), 103);
+        assert!(matches!(engine.eval("price(True)"), Err(Error::Type(_))));
+    }
+
+    #[test]
+    fn t_engine_register_errors() {
+        let mut engine = Engine::new();
+        assert!(engine.register_fn2("plus", |a: i64, b: i64| a - b).is_err());
+        assert!(engine
+            .register("one", Ty::Int, |_| Ok(Value::Int(1)))
+            .is_err());
+
+        engine.register_fn1("price", |sku: i64| sku * 100).unwrap();
+        assert!(engine.register_fn1("price", |sku: i64| sku).is_err());
+        assert_eq!(eval_int(&mut engine, "plus(price(1), 1)"), 101);
+    }
+
+    #[test]
+    fn t_engine_errors_in_earlier_code() {
+        use crate::diagnostic::WithSources;
+
+        let mut engine = Engine::new();
+        let def = "fdef x = if x < 1 then error() else fdef(x - 1)";
+        engine.run(def).unwrap();
+        match engine.run("fdef(3)") {
+            Err(Error::Runtime(e)) => {
+                let source = e.span().unwrap().source();
+                assert_eq!(engine.sources().get(source), Some(def));
+
+                let d = e.diagnostic();
+                let rendered = format!("{}", WithSources::new(engine.sources(), &d));
+                assert!(rendered.contains(def));
+                // Rendered against the wrong code, only the location is shown.
+                let rendered = format!("{}", WithCode::new("fdef(3)", &e));
+                assert!(rendered.starts_with("error: error() called\n --> 1:24\nnote:"));
+            }
+            other => panic!("Expected a runtime error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn t_engine_keeps_definitions() {
+        let mut engine = Engine::new();
+        assert!(engine.eval("tax n = n / 10").unwrap().is_none());
+        assert_eq!(eval_int(&mut engine, "tax(250)"), 25);
+
+        assert!(matches!(
+            engine.eval("rate = 5\nerror()"),
+            Err(Error::Runtime(_))
+        ));
+        assert!(matches!(engine.eval("rate"), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn t_engine_limits() {
+        let mut engine = Engine::new();
+        // Deep recursion fails by default rather than overflowing the stack.
+        match engine.eval("f n = 1 + f(n)\nf(0)") {
+            Err(Error::Runtime(e)) => assert_eq!(e.exceeded_limit(), Some(Limit::Depth)),
+            other => panic!("Expected the depth limit to be hit, got: {:?}", other),
+        }
+
+        engine.set_limits(Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        });
+
+        let runaway = "fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)\nfib(50)";
+        match engine.eval(runaway) {
+            Err(Error::Runtime(e)) => assert!(e.exceeded_limit().is_some()),
+            other => panic!("Expected the step limit to be hit, got: {:?}", other),
+        }
+        assert_eq!(eval_int(&mut engine, "let f n = n * 2 in f(21)"), 42);
+    }
+}
@@ -1,117 +1,584 @@
-use crate::builtin::{BuiltinName, B};
-use crate::tast::{Condition, Ex, NameDef, N};
-use rpds::HashTrieMap;
+use crate::diagnostic::Diagnostic;
+use crate::limits::{Budget, Limit, Limits};
+use crate::parser::{SrcSpan, WithCode};
+use crate::tast::{Addr, Binding, Condition, Ex, Lambda, NameDef, N};
+use crate::value::{Closure, Partial, Value};
+use std::fmt::{self, Display};
+use std::rc::Rc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+pub struct RuntimeError {
+    msg: String,
+    span: Option<SrcSpan>,
+    limit: Option<Limit>,
+    trace: Option<Vec<Frame>>,
+}
+
+/// A call of a fang function that was in progress when an error occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// `None` for anonymous functions.
+    pub name: Option<String>,
+    pub call_site: Option<SrcSpan>,
+}
+
+impl Frame {
+    fn of(closure: &Closure, call_site: Option<SrcSpan>) -> Frame {
+        Frame {
+            name: closure.name.as_ref().map(|n| n.to_string()),
+            call_site,
+        }
+    }
+}
+
+impl RuntimeError {
+    pub(crate) fn new(msg: String, span: Option<SrcSpan>) -> RuntimeError {
+        RuntimeError {
+            msg,
+            span,
+            limit: None,
+            trace: None,
+        }
+    }
+
+    pub(crate) fn limit(msg: String, span: Option<SrcSpan>, limit: Limit) -> RuntimeError {
+        RuntimeError {
+            msg,
+            span,
+            limit: Some(limit),
+            trace: None,
+        }
+    }
+
+    pub fn span(&self) -> Option<SrcSpan> {
+        self.span
+    }
+
+    /// The limit that stopped the evaluation, `None` for errors in the code
+    /// itself.
+    pub fn exceeded_limit(&self) -> Option<Limit> {
+        self.limit
+    }
+
+    /// Calls in progress when the error occurred, innermost first.
+    pub fn trace(&self) -> &[Frame] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Points at the error and at the call sites of its trace.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let code = if self.limit.is_some() {
+            "limit"
+        } else {
+            "runtime"
+        };
+        let mut d = Diagnostic::error(&self.msg).with_code(code).at(self.span);
+
+        // Recursive calls from the same call site are shown once.
+        let mut groups: Vec<(&Frame, usize)> = vec![];
+        for frame in self.trace() {
+            match groups.last_mut() {
+                Some((last, count)) if *last == frame => *count += 1,
+                _ => groups.push((frame, 1)),
+            }
+        }
+
+        for (frame, count) in groups.iter().take(MAX_TRACE_GROUPS) {
+            let mut msg = match &frame.name {
+                Some(name) => format!("in {}, called here", name),
+                None => "in a lambda, called here".to_string(),
+            };
+            if *count > 1 {
+                msg.push_str(&format!(" ({} times)", count));
+            }
+            d = d.with_note(msg, frame.call_site);
+        }
+
+        let hidden: usize = groups.iter().skip(MAX_TRACE_GROUPS).map(|(_, n)| n).sum();
+        if hidden > 0 {
+            d = d.with_note(format!("... and {} more calls", hidden), None);
+        }
+        d
+    }
+
+    /// Records the call stack unless the error already has one, which then
+    /// comes from a deeper point of the evaluation.
+    pub(crate) fn with_trace(mut self, stack: &[Frame]) -> RuntimeError {
+        if self.trace.is_none() {
+            self.trace = Some(stack.iter().rev().cloned().collect());
+        }
+        self
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Runtime error: {}", self.msg)
+    }
+}
+
+/// Deep recursion would print a huge trace, so only this many innermost
+/// groups of calls are shown.
+const MAX_TRACE_GROUPS: usize = 8;
+
+impl<'a, 'b> Display for WithCode<'a, 'b, RuntimeError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", WithCode::new(self.code, &self.t.diagnostic()))
+    }
+}
+
+type Result<A> = std::result::Result<A, RuntimeError>;
+
+/// Values of the variables in scope, in frames addressed by `tast::Addr`.
+/// Closures share the frames of the environment they are made in.
+#[derive(Debug, Clone, Default)]
 pub struct Env {
-    mapping: HashTrieMap<NameDef, Ex>,
+    frame: Option<Rc<Slots>>,
+}
+
+#[derive(Debug, Clone)]
+struct Slots {
+    vals: Vec<Value>,
+    parent: Option<Rc<Slots>>,
 }
 
 impl Env {
     pub fn new() -> Env {
+        Env { frame: None }
+    }
+
+    /// Makes a new innermost frame.
+    pub fn push(&self, vals: Vec<Value>) -> Env {
+        let slots = Slots {
+            vals,
+            parent: self.frame.clone(),
+        };
         Env {
-            mapping: HashTrieMap::new(),
+            frame: Some(Rc::new(slots)),
         }
     }
 
-    pub fn bind(&self, name: &NameDef, ex: Ex) -> Env {
-        let mapping = self.mapping.insert(name.clone(), ex);
-        Self { mapping }
+    /// Adds a value to the innermost frame. Closures that share the frame keep
+    /// seeing it as it was.
+    pub fn define(&mut self, val: Value) {
+        match &mut self.frame {
+            Some(frame) => Rc::make_mut(frame).vals.push(val),
+            None => *self = self.push(vec![val]),
+        }
     }
 
-    pub fn bind_many(&self, names: &[&NameDef], exs: &[Ex]) -> Env {
-        assert_eq!(names.len(), exs.len());
-        let mut mapping = self.mapping.clone();
-        for (num, &n) in names.iter().enumerate() {
-            mapping.insert_mut(n.clone(), exs[num].clone());
+    pub fn find(&self, addr: Addr) -> Option<Value> {
+        let mut frame = self.frame.as_ref()?;
+        for _ in 0..addr.depth {
+            frame = frame.parent.as_ref()?;
         }
-        Self { mapping }
+        frame.vals.get(addr.index).cloned()
     }
+}
+
+/// Evaluates a compilation unit one top-level expression at a time.
+#[derive(Clone)]
+pub struct Interp {
+    env: Env,
+}
 
-    pub fn find(&self, name: &NameDef) -> Option<Ex> {
-        self.mapping.get(name).cloned()
+impl Default for Interp {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub fn eval_ex(ex: Ex, env: &Env) -> Ex {
-    use Ex::*;
+impl Interp {
+    pub fn new() -> Interp {
+        Interp { env: Env::new() }
+    }
+
+    /// Evaluates a top-level expression. Bindings extend the environment of
+    /// everything evaluated after them and have no value of their own.
+    pub fn eval_top(&mut self, ex: &Ex) -> Result<Option<Value>> {
+        self.eval_top_in(ex, &mut Budget::new(Limits::default()))
+    }
 
-    match ex {
-        Bind(b) => {
-            let new_env = env.bind(&b.t.name.t, b.t.ex.clone());
-            eval_ex(b.t.ex.clone(), &new_env)
+    /// Like `eval_top`, but stops when the budget runs out.
+    pub fn eval_top_in(&mut self, ex: &Ex, budget: &mut Budget) -> Result<Option<Value>> {
+        let mut m = Machine::new(budget);
+        match ex {
+            Ex::Bind(b) => {
+                let val = eval_binding(b, &self.env, &mut m)?;
+                self.env.define(val);
+                Ok(None)
+            }
+            other => eval_in(other, &self.env, &mut m).map(Some),
         }
-        Lam(_) => ex,
-        Ap(a) => {
-            let ex = eval_ex(a.t.ex.clone(), env);
-            let args: Vec<_> =
-                a.t.args
-                    .iter()
-                    .map(|arg| eval_ex(arg.clone(), env))
-                    .collect();
+    }
+}
 
-            match ex {
-                BRef(n) => eval_builtin(&n, &args, env),
-                Lam(l) => {
-                    let bound: Vec<_> = l.t.bound.iter().map(|n| n.t.as_ref()).collect();
-                    let new_env = env.bind_many(&bound, &args);
-                    eval_ex(l.t.body.clone(), &new_env)
+pub fn eval_ex(ex: &Ex, env: &Env) -> Result<Value> {
+    eval_ex_in(ex, env, &mut Budget::new(Limits::default()))
+}
+
+/// Evaluates an expression within the budget.
+pub fn eval_ex_in(ex: &Ex, env: &Env, budget: &mut Budget) -> Result<Value> {
+    eval_in(ex, env, &mut Machine::new(budget))
+}
+
+/// State of a single evaluation.
+struct Machine<'a> {
+    budget: &'a mut Budget,
+    /// Calls in progress, innermost last. A tail call replaces the frame of
+    /// its caller.
+    stack: Vec<Frame>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(budget: &'a mut Budget) -> Machine<'a> {
+        Machine {
+            budget,
+            stack: vec![],
+        }
+    }
+}
+
+fn eval_in(ex: &Ex, env: &Env, m: &mut Machine) -> Result<Value> {
+    m.budget
+        .enter(ex.span())
+        .map_err(|e| e.with_trace(&m.stack))?;
+    let depth = m.stack.len();
+    let result = eval_loop(ex, env, m).map_err(|e| e.with_trace(&m.stack));
+    m.stack.truncate(depth);
+    m.budget.leave();
+    result
+}
+
+/// Expressions in tail position, i.e. let bodies, condition branches and
+/// bodies of called closures, are evaluated in the same loop rather than
+/// recursively, so tail calls run in constant stack.
+fn eval_loop(ex: &Ex, env: &Env, m: &mut Machine) -> Result<Value> {
+    let mut ex = ex.clone();
+    let mut env = env.clone();
+    let mut in_call = false;
+
+    loop {
+        m.budget.step(ex.span())?;
+        let next = match &ex {
+            Ex::Bind(b) => match &b.t.ex {
+                Ex::Lam(_) => return eval_binding(b, &env, m),
+                other => other.clone(),
+            },
+            Ex::Let(l) => {
+                env = env.push(Vec::with_capacity(l.t.bindings.len()));
+                for b in &l.t.bindings {
+                    let val = eval_binding(b, &env, m)?;
+                    env.define(val);
                 }
-                other => panic!("Unexpected application expression: {}", other),
+                l.t.body.clone()
             }
-        }
-        Cond(c) => {
-            let Condition { pred, then, els } = c.t.as_ref();
-            let pred = eval_ex(pred.clone(), env);
-            if let ConstBool(b) = pred {
-                if b {
-                    eval_ex(then.clone(), env)
-                } else {
-                    eval_ex(els.clone(), env)
+            Ex::Lam(l) => return Ok(make_closure(l, None, &env)),
+            Ex::Ap(a) => {
+                let func = eval_in(&a.t.ex, &env, m)?;
+                let args =
+                    a.t.args
+                        .iter()
+                        .map(|arg| eval_in(arg, &env, m))
+                        .collect::<Result<Vec<_>>>()?;
+                match call(func, args, a.span, m)? {
+                    Step::Done(val) => return Ok(val),
+                    Step::Eval(closure, body_env) => {
+                        let frame = Frame::of(&closure, a.span);
+                        match m.stack.last_mut() {
+                            Some(last) if in_call => *last = frame,
+                            _ => m.stack.push(frame),
+                        }
+                        in_call = true;
+                        env = body_env;
+                        closure.lam.body.clone()
+                    }
                 }
-            } else {
-                panic!("Unexpected boolean expression: {}", pred);
             }
-        }
-        BRef(r) => r.into(),
-        URef(r) => env.find(&r).unwrap_or_else(|| panic!("Unkown name: {}", r)),
-        ConstInt(_) => ex,
-        ConstBool(_) => ex,
+            Ex::Cond(c) => {
+                let Condition { pred, then, els } = c.t.as_ref();
+                match eval_in(pred, &env, m)? {
+                    Value::Bool(true) => then.clone(),
+                    Value::Bool(false) => els.clone(),
+                    other => {
+                        return Err(RuntimeError::new(
+                            format!("Expected a boolean condition, found: {}", other),
+                            pred.span(),
+                        ))
+                    }
+                }
+            }
+            Ex::BRef(r) => return Ok(Value::Builtin(r.clone())),
+            Ex::URef(r) => {
+                return env.find(r.t.addr).ok_or_else(|| {
+                    RuntimeError::new(format!("Unknown name: {}", r.t.name), r.span)
+                })
+            }
+            Ex::ConstInt(n) => return Ok(Value::Int(*n.t)),
+            Ex::ConstBool(b) => return Ok(Value::Bool(*b.t)),
+        };
+        ex = next;
     }
 }
 
-pub fn eval_builtin(name: &N<BuiltinName>, args: &[Ex], _env: &Env) -> Ex {
-    use Ex::*;
+/// Evaluates the right-hand side of a binding. Functions become closures that
+/// can refer to themselves by the bound name.
+fn eval_binding(b: &N<Binding>, env: &Env, m: &mut Machine) -> Result<Value> {
+    match &b.t.ex {
+        Ex::Lam(l) => Ok(make_closure(l, Some(&b.t.name), env)),
+        other => eval_in(other, env, m),
+    }
+}
+
+/// Closes over the defining environment, which makes scoping lexical.
+fn make_closure(lam: &N<Lambda>, name: Option<&N<NameDef>>, env: &Env) -> Value {
+    Value::Closure(Rc::new(Closure {
+        lam: lam.clone(),
+        name: name.map(|n| n.t.as_ref().clone()),
+        env: env.clone(),
+    }))
+}
 
-    if name.t == B.plus.t {
-        let lhs = &args[0];
-        let rhs = &args[1];
+/// What is left to do to finish a call.
+enum Step {
+    Done(Value),
+    /// The body of the closure, which the caller evaluates in the environment
+    /// in place of the call.
+    Eval(Rc<Closure>, Env),
+}
 
-        match (lhs, rhs) {
-            (ConstInt(n1), ConstInt(n2)) => return ConstInt(n1 + n2),
-            _ => panic!("Cannot add \n1: {}\n2: {}", lhs, rhs),
+/// Calls a function value and evaluates the result to completion.
+fn apply(func: Value, args: Vec<Value>, span: Option<SrcSpan>, m: &mut Machine) -> Result<Value> {
+    match call(func, args, span, m)? {
+        Step::Done(val) => Ok(val),
+        Step::Eval(closure, env) => {
+            m.stack.push(Frame::of(&closure, span));
+            let result = eval_in(&closure.lam.body, &env, m);
+            m.stack.pop();
+            result
         }
     }
+}
 
-    if name.t == B.minus.t {
-        let lhs = &args[0];
-        let rhs = &args[1];
+/// Calls a function value. Too few arguments make a partial application, extra
+/// ones are passed on to the result of the call.
+fn call(func: Value, mut args: Vec<Value>, span: Option<SrcSpan>, m: &mut Machine) -> Result<Step> {
+    let arity = func
+        .arity()
+        .ok_or_else(|| RuntimeError::new(format!("Cannot apply a non-function: {}", func), span))?;
 
-        match (lhs, rhs) {
-            (ConstInt(n1), ConstInt(n2)) => return ConstInt(n1 - n2),
-            _ => panic!("Cannot subtract \n1: {}\n2: {}", lhs, rhs),
+    if args.len() < arity {
+        return Ok(Step::Done(Value::Partial(Rc::new(Partial { func, args }))));
+    }
+    let rest = args.split_off(arity);
+    if !rest.is_empty() {
+        let result = apply(func, args, span, m)?;
+        return call(result, rest, span, m);
+    }
+
+    match func {
+        Value::Builtin(b) => (b.func)(&args)
+            .map(Step::Done)
+            .map_err(|msg| RuntimeError::new(msg, span)),
+        Value::Closure(c) => {
+            // The frame of a call holds the function itself if it's named,
+            // then the arguments.
+            let mut vals = Vec::with_capacity(args.len() + 1);
+            if c.name.is_some() {
+                vals.push(Value::Closure(c.clone()));
+            }
+            vals.extend(args);
+            let env = c.env.push(vals);
+            Ok(Step::Eval(c, env))
+        }
+        Value::Compiled(_) => Err(RuntimeError::new(
+            format!("Cannot apply a compiled function: {}", func),
+            span,
+        )),
+        Value::Partial(p) => {
+            let all_args = p.args.iter().cloned().chain(args).collect();
+            call(p.func.clone(), all_args, span, m)
         }
+        Value::Int(_) | Value::Bool(_) => unreachable!("Only functions have an arity"),
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lower::lower_unit;
+    use crate::parser::parse;
+    use crate::resolve::resolve_unit;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
 
-    if name.t == B.less.t {
-        let lhs = &args[0];
-        let rhs = &args[1];
+    fn eval_str(code: &str) -> Result<Value> {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        let exs = lower_unit(&unit, &res).unwrap();
+        eval_ex(exs.last().unwrap(), &Env::new())
+    }
 
-        match (lhs, rhs) {
-            (ConstInt(n1), ConstInt(n2)) => return ConstBool(n1 < n2),
-            _ => panic!("Cannot compare \n1: {}\n2: {}", lhs, rhs),
+    fn assert_int(val: Result<Value>, expected: i64) {
+        match val.unwrap() {
+            Value::Int(n) => assert_eq!(n, expected),
+            other => panic!("Expected {}, got: {}", expected, other),
         }
     }
 
-    panic!("Unknown builtin name: {}", name.t)
+    #[test]
+    fn t_eval_closures() {
+        assert_int(
+            eval_str(r"let make_adder n = \x -> x + n, add2 = make_adder(2) in add2(40)"),
+            42,
+        );
+        assert_int(eval_str("let x = 1, f y = x + y in let x = 100 in f(1)"), 2);
+        assert_int(
+            eval_str(r"let twice f = \x -> f(f(x)), inc = \x -> x + 1 in twice(twice(inc))(0)"),
+            4,
+        );
+    }
+
+    #[test]
+    fn t_eval_partial_application() {
+        assert_int(eval_str("plus(1)(2)"), 3);
+        assert_int(eval_str(r"(\x y z -> x - y + z)(10)(1, 2)"), 11);
+
+        let show = |code| format!("{}", eval_str(code).unwrap());
+        assert_eq!(show("minus(5)"), "<builtin minus>(5, ...)");
+        assert_eq!(show("let f x = x in f"), "<function f>");
+        assert_eq!(show("1 < 2"), "True");
+    }
+
+    #[test]
+    fn t_eval_top_level() {
+        let unit = parse("n = 20\nfib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)\nfib(n)\nn")
+            .unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        let exs = lower_unit(&unit, &res).unwrap();
+
+        let mut interp = Interp::new();
+        let vals = exs
+            .iter()
+            .map(|ex| interp.eval_top(ex).unwrap().map(|v| format!("{}", v)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vals,
+            vec![
+                None,
+                None,
+                Some("10946".to_string()),
+                Some("20".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn t_eval_tail_calls() {
+        assert_int(
+            eval_str(
+                "let count n acc = if n < 1 then acc else count(n - 1, acc + 1)
+                 in count(100000, 0)",
+            ),
+            100000,
+        );
+        assert_int(
+            eval_str(
+                "let down n = if n < 1 then 0 else let m = n - 1 in down(m)
+                 in down(100000)",
+            ),
+            0,
+        );
+    }
+
+    fn eval_limited(code: &str, limits: Limits) -> Result<Value> {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        let exs = lower_unit(&unit, &res).unwrap();
+        eval_ex_in(exs.last().unwrap(), &Env::new(), &mut Budget::new(limits))
+    }
+
+    #[test]
+    fn t_eval_limits() {
+        let fib = "let fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2) in fib(50)";
+        let steps = Limits {
+            max_steps: Some(10_000),
+            ..Limits::default()
+        };
+        let err = eval_limited(fib, steps).unwrap_err();
+        assert_eq!(err.exceeded_limit(), Some(Limit::Steps));
+        assert!(err.msg.contains("after 10001 steps"));
+
+        let depth = Limits {
+            max_depth: Some(100),
+            ..Limits::default()
+        };
+        let err = eval_limited("let f n = 1 + f(n) in f(0)", depth).unwrap_err();
+        assert_eq!(err.exceeded_limit(), Some(Limit::Depth));
+        // Tail calls don't count towards the depth.
+        assert_int(
+            eval_limited("let f n = if n < 1 then 0 else f(n - 1) in f(1000)", depth),
+            0,
+        );
+
+        let time = Limits {
+            timeout: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        let err = eval_limited("let f n = f(n) in f(0)", time).unwrap_err();
+        assert_eq!(err.exceeded_limit(), Some(Limit::Time));
+
+        let err = eval_str("error()").unwrap_err();
+        assert_eq!(err.exceeded_limit(), None);
+    }
+
+    #[test]
+    fn t_eval_deep_recursion() {
+        let deep = "let deep n = if n < 1 then 0 else 1 + deep(n - 1) in ";
+        assert_int(eval_str(&format!("{}deep(100)", deep)), 100);
+        let err = eval_str(&format!("{}deep(200000)", deep)).unwrap_err();
+        assert_eq!(err.exceeded_limit(), Some(Limit::Depth));
+        assert!(eval_str("let f n = 1 + f(n) in f(0)").is_err());
+    }
+
+    #[test]
+    fn t_eval_stack_trace() {
+        let code = "let check n = if n < 0 then error() else n,
+                        down n = if n < 1 then check(n - 1) else 1 + down(n - 1)
+                    in down(3)";
+        let err = eval_str(code).unwrap_err();
+        let trace: Vec<_> = err
+            .trace()
+            .iter()
+            .map(|f| (f.name.clone().unwrap(), f.call_site.unwrap().offset.start))
+            .collect();
+
+        let check_call = code.find("check(n - 1)").unwrap();
+        let rec_call = code.find("down(n - 1)").unwrap();
+        let first_call = code.find("down(3)").unwrap();
+        // `check` is a tail call, so it replaces the frame of `down(0)`.
+        assert_eq!(
+            trace,
+            vec![
+                ("check".to_string(), check_call),
+                ("down".to_string(), rec_call),
+                ("down".to_string(), rec_call),
+                ("down".to_string(), first_call),
+            ]
+        );
+
+        let rendered = format!("{}", WithCode::new(code, &err));
+        assert!(rendered.contains("in down, called here (2 times)"));
+    }
+
+    #[test]
+    fn t_eval_runtime_errors() {
+        let err = eval_str("if 1 < 2 then error() else 0").unwrap_err();
+        assert_eq!(err.msg, "error() called");
+        assert_eq!(err.span.unwrap().offset.start, 14);
+
+        let err = eval_str("1 + 2(3)").unwrap_err();
+        assert!(err.msg.starts_with("Cannot apply a non-function"));
+        assert_eq!(err.span.unwrap().offset.start, 4);
+    }
 }
@@ -0,0 +1,29 @@
+//! Parser, type checker and interpreter of the fang language.
+//!
+//! Code goes through [`parser::parse`], [`resolve::resolve_unit`],
+//! [`lower::lower_unit`] and [`typeck::infer_unit`], and the typed
+//! expressions are evaluated by [`interp::Interp`], or compiled to bytecode
+//! and run by [`vm::Vm`]. Errors of every phase convert to a
+//! [`diagnostic::Diagnostic`], which is rendered against the source code with
+//! [`parser::WithCode`].
+//!
+//! [`Engine`] runs the whole pipeline and is the easiest way to embed fang.
+
+pub mod builtin;
+pub(crate) mod bytecode;
+pub mod diagnostic;
+pub mod embed;
+pub mod interp;
+pub mod limits;
+pub mod lower;
+pub mod parser;
+pub mod resolve;
+pub mod tast;
+pub mod ty;
+pub mod typeck;
+pub mod value;
+pub mod vm;
+
+pub use embed::{Engine, Error, Item};
+pub use limits::Limits;
+pub use value::Value;
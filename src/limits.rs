@@ -0,0 +1,117 @@
+use crate::interp::RuntimeError;
+use crate::parser::SrcSpan;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+/// Depth limit of the default limits. A level of the interpreter's recursion
+/// takes several KiB of stack in debug builds, so this leaves room to spare in
+/// the 2 MiB of a spawned thread.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Bounds on a single evaluation, `None` means unbounded. By default only the
+/// depth is bounded, which keeps deep recursion from overflowing the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Number of reduction steps, roughly one per evaluated expression.
+    pub max_steps: Option<u64>,
+    /// Nesting of evaluations that aren't in tail position, which is also the
+    /// depth of the interpreter's own recursion. Without a bound, or with one
+    /// that doesn't fit the stack, deep recursion crashes the process.
+    pub max_depth: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: None,
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            timeout: None,
+        }
+    }
+}
+
+/// The limit an evaluation was stopped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    Depth,
+    Time,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "step limit"),
+            Limit::Depth => write!(f, "depth limit"),
+            Limit::Time => write!(f, "time limit"),
+        }
+    }
+}
+
+/// Reading the clock on every step is comparatively slow, so the deadline is
+/// only checked this often.
+const CLOCK_INTERVAL: u64 = 256;
+
+/// Tracks how much of the limits an evaluation has used up.
+#[derive(Debug)]
+pub struct Budget {
+    limits: Limits,
+    steps: u64,
+    depth: usize,
+    start: Instant,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            steps: 0,
+            depth: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub(crate) fn step(&mut self, span: Option<SrcSpan>) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        if self.limits.max_steps.is_some_and(|max| self.steps > max) {
+            return Err(self.exceeded(Limit::Steps, span));
+        }
+        if self.steps % CLOCK_INTERVAL == 0 {
+            if let Some(timeout) = self.limits.timeout {
+                if self.start.elapsed() > timeout {
+                    return Err(self.exceeded(Limit::Time, span));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn enter(&mut self, span: Option<SrcSpan>) -> Result<(), RuntimeError> {
+        self.depth += 1;
+        if self.limits.max_depth.is_some_and(|max| self.depth > max) {
+            self.depth -= 1;
+            return Err(self.exceeded(Limit::Depth, span));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn exceeded(&self, limit: Limit, span: Option<SrcSpan>) -> RuntimeError {
+        let msg = format!(
+            "Evaluation stopped by the {} after {} steps at depth {} ({} ms)",
+            limit,
+            self.steps,
+            self.depth,
+            self.start.elapsed().as_millis()
+        );
+        RuntimeError::limit(msg, span, limit)
+    }
+}
@@ -0,0 +1,510 @@
+use crate::builtin::{Builtin, B};
+use crate::parser::{self, combine_results_2, combine_results_3, combine_results_n};
+use crate::parser::{CompilationUnit, Ident, ParsingError};
+use crate::resolve::{Res, Resolution};
+use crate::tast::{Addr, Application, Binding, Condition, Ex, Lambda, Let, NameDef, Var, N};
+use crate::ty::Ty;
+use std::sync::Arc;
+
+type Result<A> = std::result::Result<A, Vec<ParsingError>>;
+
+/// Names in scope while lowering, laid out like the frames of the
+/// interpreter's environment, innermost last.
+#[derive(Debug, Default)]
+pub(crate) struct Scopes {
+    frames: Vec<Vec<NameDef>>,
+}
+
+impl Scopes {
+    /// Scopes with the given top-level names, in the order of definition.
+    pub(crate) fn new(globals: &[NameDef]) -> Scopes {
+        Scopes {
+            frames: vec![globals.to_vec()],
+        }
+    }
+
+    fn push(&mut self, names: Vec<NameDef>) {
+        self.frames.push(names);
+    }
+
+    fn pop(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Adds a name to the innermost frame.
+    fn define(&mut self, name: &NameDef) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.push(name.clone());
+        }
+    }
+
+    /// The innermost definition of the name wins.
+    fn addr(&self, name: &NameDef) -> Option<Addr> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, frame)| {
+                let index = frame.iter().rposition(|n| n == name)?;
+                Some(Addr { depth, index })
+            })
+    }
+}
+
+pub fn lower_unit(unit: &CompilationUnit, res: &Resolution) -> Result<Vec<Ex>> {
+    lower_unit_in(unit, res, &[])
+}
+
+/// Lowers code that can refer to earlier top-level definitions.
+pub fn lower_unit_in(
+    unit: &CompilationUnit,
+    res: &Resolution,
+    globals: &[NameDef],
+) -> Result<Vec<Ex>> {
+    let mut scopes = Scopes::new(globals);
+    let mut results = vec![];
+    for ex in &unit.nodes {
+        results.push(lower_ex(ex, res, &mut scopes));
+        if let parser::Ex::Binding(b) = ex {
+            scopes.define(&def(&b.t.lhs, res));
+        }
+    }
+    combine_results_n(results)
+}
+
+pub(crate) fn lower_ex(ex: &parser::Ex, res: &Resolution, scopes: &mut Scopes) -> Result<Ex> {
+    match ex {
+        parser::Ex::Application(ap) => lower_ap(ap, res, scopes).map(|x| x.into()),
+        parser::Ex::Binding(bind) => lower_binding(bind, res, scopes).map(|x| x.into()),
+        parser::Ex::Condition(cond) => lower_cond(cond, res, scopes).map(|x| x.into()),
+        parser::Ex::ConstBool(b) => Ok(Ex::ConstBool(N::spanned(*b.t, Ty::Bool, b.src_span()))),
+        parser::Ex::ConstInt(i) => Ok(Ex::ConstInt(N::spanned(*i.t, Ty::Int, i.src_span()))),
+        parser::Ex::Identifier(ident) => lower_ref(ident, res, scopes),
+        parser::Ex::Infix(infix) => lower_infix(infix, res, scopes).map(|x| x.into()),
+        parser::Ex::Lambda(lam) => lower_lambda(lam, None, res, scopes).map(|x| x.into()),
+        parser::Ex::Let(l) => lower_let(l, res, scopes).map(|x| x.into()),
+        parser::Ex::Prefix(prefix) => lower_prefix(prefix, res, scopes).map(|x| x.into()),
+        // Parsing has already reported the error.
+        parser::Ex::Error(e) => Err(vec![ParsingError::at("Syntax error".to_string(), e)]),
+    }
+}
+
+/// A node at the same location as `src` whose type is yet to be inferred.
+fn node<T, U>(src: &parser::N<T>, t: U) -> N<U> {
+    N::spanned(t, Ty::Unknown, src.src_span())
+}
+
+/// The name a binding or a parameter defines.
+fn def(ident: &parser::N<Ident>, res: &Resolution) -> NameDef {
+    res.def(ident)
+        .unwrap_or_else(|| panic!("Definition of {} wasn't resolved", ident.t.0))
+}
+
+fn lower_ident(ident: &parser::N<Ident>, res: &Resolution) -> N<NameDef> {
+    node(ident, def(ident, res))
+}
+
+fn lower_ref(ident: &parser::N<Ident>, res: &Resolution, scopes: &Scopes) -> Result<Ex> {
+    let name = match res.get(ident) {
+        Some(Res::Builtin(builtin)) => return Ok(builtin.into()),
+        Some(Res::User(name)) => name,
+        None => NameDef::new(&ident.t.0),
+    };
+    match scopes.addr(&name) {
+        Some(addr) => Ok(node(ident, Var { name, addr }).into()),
+        None => Err(vec![ParsingError::at(
+            format!("Unbound name: {}", ident.t.0),
+            ident,
+        )]),
+    }
+}
+
+fn lower_ap(
+    ap: &parser::N<parser::Ap>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Application>> {
+    let ex = lower_ex(&ap.t.receiver, res, scopes);
+    let args = combine_results_n(
+        ap.t.args
+            .iter()
+            .map(|arg| lower_ex(arg, res, scopes))
+            .collect(),
+    );
+
+    combine_results_2(ex, args).map(|(ex, args)| node(ap, Application { ex, args }))
+}
+
+/// `f x y = body` becomes `f = λ (x, y) -> body`, while a binding without
+/// parameters keeps its right-hand side as is. A function can refer to itself
+/// by the bound name, which the caller adds to the enclosing scope.
+fn lower_binding(
+    bind: &parser::N<parser::Bind>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Binding>> {
+    let name = lower_ident(&bind.t.lhs, res);
+
+    let ex = match &bind.t.rhs {
+        _ if !bind.t.params.is_empty() => {
+            scopes.push(self_and_params(&name, &bind.t.params, res));
+            let body = lower_ex(&bind.t.rhs, res, scopes);
+            scopes.pop();
+            let bound = bind.t.params.iter().map(|p| lower_ident(p, res)).collect();
+            node(bind, lambda(bound, body?)).into()
+        }
+        parser::Ex::Lambda(lam) => lower_lambda(lam, Some(&name), res, scopes)?.into(),
+        other => lower_ex(other, res, scopes)?,
+    };
+
+    Ok(node(bind, Binding { name, ex }))
+}
+
+/// The frame of a call: the function itself if it's named, then arguments.
+fn self_and_params(
+    name: &N<NameDef>,
+    params: &[parser::N<Ident>],
+    res: &Resolution,
+) -> Vec<NameDef> {
+    let params = params.iter().map(|p| def(p, res));
+    std::iter::once(name.t.as_ref().clone())
+        .chain(params)
+        .collect()
+}
+
+fn lower_cond(
+    cond: &parser::N<parser::Cond>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Condition>> {
+    let pred = lower_ex(&cond.t.pred, res, scopes);
+    let then = lower_ex(&cond.t.then, res, scopes);
+    let els = lower_ex(&cond.t.els, res, scopes);
+
+    combine_results_3(pred, then, els)
+        .map(|(pred, then, els)| node(cond, Condition { pred, then, els }))
+}
+
+fn lower_infix(
+    infix: &parser::N<parser::InfixEx>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Application>> {
+    let op = &infix.t.op;
+    let builtin =
+        infix_builtin(&op.t.0).ok_or_else(|| vec![ParsingError::at(unsupported_op(&op.t.0), op)]);
+    let lhs = lower_ex(&infix.t.lhs, res, scopes);
+    let rhs = lower_ex(&infix.t.rhs, res, scopes);
+
+    combine_results_3(builtin, lhs, rhs).map(|(builtin, lhs, rhs)| {
+        let app = Application {
+            ex: builtin.into(),
+            args: vec![lhs, rhs],
+        };
+        node(infix, app)
+    })
+}
+
+fn lower_prefix(
+    prefix: &parser::N<parser::PrefixEx>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Application>> {
+    let op = &prefix.t.op;
+    let builtin =
+        prefix_builtin(&op.t.0).ok_or_else(|| vec![ParsingError::at(unsupported_op(&op.t.0), op)]);
+    let body = lower_ex(&prefix.t.body, res, scopes);
+
+    combine_results_2(builtin, body).map(|(builtin, body)| {
+        let app = Application {
+            ex: builtin.into(),
+            args: vec![body],
+        };
+        node(prefix, app)
+    })
+}
+
+fn lower_lambda(
+    lam_node: &parser::N<parser::Lam>,
+    name: Option<&N<NameDef>>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Lambda>> {
+    let frame = match name {
+        Some(name) => self_and_params(name, &lam_node.t.params, res),
+        None => lam_node.t.params.iter().map(|p| def(p, res)).collect(),
+    };
+    scopes.push(frame);
+    let body = lower_ex(&lam_node.t.body, res, scopes);
+    scopes.pop();
+
+    let bound = lam_node
+        .t
+        .params
+        .iter()
+        .map(|p| lower_ident(p, res))
+        .collect();
+    Ok(node(lam_node, lambda(bound, body?)))
+}
+
+/// The bindings of a let share one frame, each one sees those before it.
+fn lower_let(
+    l: &parser::N<parser::LetEx>,
+    res: &Resolution,
+    scopes: &mut Scopes,
+) -> Result<N<Let>> {
+    scopes.push(vec![]);
+    let mut bindings = vec![];
+    for b in &l.t.bindings {
+        bindings.push(lower_binding(b, res, scopes));
+        scopes.define(&def(&b.t.lhs, res));
+    }
+    let body = lower_ex(&l.t.body, res, scopes);
+    scopes.pop();
+
+    combine_results_2(combine_results_n(bindings), body)
+        .map(|(bindings, body)| node(l, Let { bindings, body }))
+}
+
+fn lambda(bound: Vec<N<NameDef>>, body: Ex) -> Lambda {
+    let mut free = free_vars(&body);
+    free.retain(|n| !bound.iter().any(|b| b.t == n.t));
+    Lambda { bound, free, body }
+}
+
+/// User names referenced but not bound inside the expression, in the order of
+/// their first occurrence. Nested lambdas already know their free names, so
+/// their bodies aren't traversed again.
+fn free_vars(ex: &Ex) -> Vec<N<NameDef>> {
+    let mut free = vec![];
+    collect_free_vars(ex, &mut free);
+    free
+}
+
+fn collect_free_vars(ex: &Ex, free: &mut Vec<N<NameDef>>) {
+    let mut add = |names: Vec<N<NameDef>>, bound: &[&NameDef]| {
+        for n in names {
+            if !bound.contains(&n.t.as_ref()) && !free.iter().any(|f| f.t == n.t) {
+                free.push(n);
+            }
+        }
+    };
+
+    match ex {
+        Ex::Bind(b) => add(free_vars(&b.ex), &[&b.name.t]),
+        Ex::Let(l) => {
+            let mut bound = vec![];
+            for b in &l.bindings {
+                bound.push(b.name.t.as_ref());
+                add(free_vars(&b.ex), &bound);
+            }
+            add(free_vars(&l.body), &bound);
+        }
+        Ex::Lam(l) => add(l.free.clone(), &[]),
+        Ex::Ap(a) => {
+            add(free_vars(&a.ex), &[]);
+            a.args.iter().for_each(|arg| add(free_vars(arg), &[]));
+        }
+        Ex::Cond(c) => {
+            add(free_vars(&c.pred), &[]);
+            add(free_vars(&c.then), &[]);
+            add(free_vars(&c.els), &[]);
+        }
+        Ex::URef(r) => add(vec![r.with(r.t.name.clone(), r.ty.clone())], &[]),
+        Ex::BRef(_) | Ex::ConstInt(_) | Ex::ConstBool(_) => {}
+    }
+}
+
+fn infix_builtin(op: &str) -> Option<Arc<Builtin>> {
+    let name = match op {
+        "+" => "plus",
+        "-" => "minus",
+        "*" => "times",
+        "/" => "div",
+        "<" => "less",
+        "<=" => "less_eq",
+        ">" => "greater",
+        ">=" => "greater_eq",
+        "==" => "eq",
+        _ => return None,
+    };
+    Some(B[name].clone())
+}
+
+fn prefix_builtin(op: &str) -> Option<Arc<Builtin>> {
+    match op {
+        "-" => Some(B["neg"].clone()),
+        _ => None,
+    }
+}
+
+fn unsupported_op(op: &str) -> String {
+    format!("Unsupported operator: {}", op)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interp::{eval_ex, Env};
+    use crate::parser::parse;
+    use crate::resolve::resolve_unit;
+    use crate::value::Value;
+
+    fn lower_str(code: &str) -> Result<Vec<Ex>> {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        lower_unit(&unit, &res)
+    }
+
+    fn eval_last(code: &str) -> Value {
+        let exs = lower_str(code).unwrap();
+        eval_ex(exs.last().unwrap(), &Env::new()).unwrap()
+    }
+
+    fn assert_int(val: Value, expected: i64) {
+        match val {
+            Value::Int(n) => assert_eq!(n, expected),
+            other => panic!("Expected {}, got: {}", expected, other),
+        }
+    }
+
+    #[test]
+    fn t_lower_operators() {
+        assert_int(eval_last("1 + 2 - 3"), 0);
+        assert_int(eval_last("-(1 + 2) + 10"), 7);
+        assert_int(eval_last("minus(plus(1, 2), 3)"), 0);
+
+        match eval_last("1 < 2") {
+            Value::Bool(b) => assert!(b),
+            other => panic!("Expected True, got: {}", other),
+        }
+    }
+
+    #[test]
+    fn t_lower_lambda_and_let() {
+        assert_int(eval_last(r"(\x y -> x + y)(1, 2)"), 3);
+        assert_int(eval_last("let a = 1, b = a + 1 in a + b"), 3);
+        assert_int(
+            eval_last(
+                "let fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)
+                 in fib(10)",
+            ),
+            89,
+        );
+    }
+
+    fn free_names(code: &str) -> Vec<String> {
+        match lower_str(code).unwrap().pop().unwrap() {
+            Ex::Lam(l) => l.free.iter().map(|n| n.t.to_string()).collect(),
+            Ex::Bind(b) => match &b.ex {
+                Ex::Lam(l) => l.free.iter().map(|n| n.t.to_string()).collect(),
+                other => panic!("Expected a lambda, got: {}", other),
+            },
+            other => panic!("Expected a lambda, got: {}", other),
+        }
+    }
+
+    /// Addresses of the references in the last expression, in order.
+    fn addrs(code: &str) -> Vec<(String, usize, usize)> {
+        fn collect(ex: &Ex, out: &mut Vec<(String, usize, usize)>) {
+            match ex {
+                Ex::URef(r) => out.push((r.t.name.to_string(), r.t.addr.depth, r.t.addr.index)),
+                Ex::Bind(b) => collect(&b.ex, out),
+                Ex::Let(l) => {
+                    l.bindings.iter().for_each(|b| collect(&b.ex, out));
+                    collect(&l.body, out);
+                }
+                Ex::Lam(l) => collect(&l.body, out),
+                Ex::Ap(a) => {
+                    collect(&a.ex, out);
+                    a.args.iter().for_each(|arg| collect(arg, out));
+                }
+                Ex::Cond(c) => {
+                    collect(&c.pred, out);
+                    collect(&c.then, out);
+                    collect(&c.els, out);
+                }
+                Ex::BRef(_) | Ex::ConstInt(_) | Ex::ConstBool(_) => {}
+            }
+        }
+        let mut out = vec![];
+        collect(lower_str(code).unwrap().last().unwrap(), &mut out);
+        out
+    }
+
+    #[test]
+    fn t_lower_addresses() {
+        let addr = |name: &str, depth, index| (name.to_string(), depth, index);
+        assert_eq!(
+            addrs("n = 20\nfib n = if n < 2 then n else fib(n - 1)"),
+            vec![
+                addr("n", 0, 1),
+                addr("n", 0, 1),
+                addr("fib", 0, 0),
+                addr("n", 0, 1)
+            ]
+        );
+        assert_eq!(
+            addrs("a = 1\nb = 2\nlet x = b, y = x in \\z -> a + y + z"),
+            vec![
+                addr("b", 1, 1),
+                addr("x", 0, 0),
+                addr("a", 2, 0),
+                addr("y", 1, 1),
+                addr("z", 0, 0)
+            ]
+        );
+        assert_int(
+            eval_last("let x = 1, f y = x + y in let x = 100 in f(x)"),
+            101,
+        );
+    }
+
+    #[test]
+    fn t_lower_free_vars() {
+        assert_eq!(
+            free_names("a = 1\nb = 2\n\\x -> x + a + b + a"),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            free_names("fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)"),
+            vec!["fib"]
+        );
+        assert_eq!(
+            free_names("y = 1\n\\x -> let z = x + y, w = z in \\v -> v + w"),
+            vec!["y"]
+        );
+    }
+
+    #[test]
+    fn t_lower_in_source() {
+        use crate::parser::SourceId;
+
+        let ex = lower_str(r"let a = 1 in \x -> a + x")
+            .unwrap()
+            .pop()
+            .unwrap();
+        let span = ex.span().unwrap();
+        let moved = ex.in_source(SourceId(3));
+        assert_eq!(moved.span(), Some(span.in_source(SourceId(3))));
+        match &moved {
+            Ex::Let(l) => {
+                assert_eq!(l.bindings[0].name.span.unwrap().source(), SourceId(3));
+                assert_eq!(l.body.span().unwrap().source(), SourceId(3));
+            }
+            other => panic!("Expected a let, got: {}", other),
+        }
+    }
+
+    #[test]
+    fn t_lower_all_operators() {
+        assert_int(eval_last("2 + 3 * 4 - 10 / 3"), 11);
+        assert_int(eval_last("mod(17, 5)"), 2);
+
+        let show = |code| format!("{}", eval_last(code));
+        assert_eq!(show("1 + 1 == 2"), "True");
+        assert_eq!(show("1 <= 0"), "False");
+        assert_eq!(show("2 > 1"), "True");
+        assert_eq!(show("(1 >= 2) == False"), "True");
+    }
+}
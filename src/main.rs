@@ -1,32 +1,76 @@
-mod builtin;
-mod interp;
-mod parser;
-mod tast;
-mod ty;
+mod repl;
 
 use anyhow::{anyhow, Context};
+use fang::diagnostic::Diagnostic;
+use fang::interp::RuntimeError;
+use fang::parser::{ParsingError, WithCode};
+use fang::typeck::TypeError;
+use fang::{interp, lower, parser, resolve, typeck, vm};
 use std::fs;
 use std::path::PathBuf;
 
 mod cli {
-    use clap::{App, Arg, ArgGroup};
+    use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 
     pub fn mk<'a, 'b>() -> App<'a, 'b> {
         let dump_ast = Arg::with_name("dump-ast").long("--ddump-ast");
+        let dump_tast = Arg::with_name("dump-tast").long("--ddump-tast");
         let debug_group = ArgGroup::with_name("debug")
-            .args(&["dump-ast"])
+            .args(&["dump-ast", "dump-tast"])
             .multiple(false);
 
         let files = Arg::with_name("file").required(true);
+        let error_format = Arg::with_name("error-format")
+            .long("--error-format")
+            .help("Prints errors as text or as one JSON object per line")
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .default_value("human")
+            .global(true);
+        let backend = Arg::with_name("backend")
+            .long("--backend")
+            .help("Evaluates with the tree-walking interpreter or the bytecode VM")
+            .takes_value(true)
+            .possible_values(&["interp", "vm"])
+            .default_value("interp");
+        let run = SubCommand::with_name("run")
+            .about("Evaluates a file and prints the value of each top-level expression")
+            .arg(files.clone())
+            .arg(backend);
+        let repl = SubCommand::with_name("repl").about("Starts an interactive session");
+
         App::new("fangc")
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(dump_ast)
+            .arg(dump_tast)
             .group(debug_group)
             .arg(files)
+            .arg(error_format)
+            .subcommand(run)
+            .subcommand(repl)
+    }
+
+    /// Whether `--error-format=json` was given, before or after the subcommand.
+    pub fn json_errors(matches: &ArgMatches) -> bool {
+        let args = match matches.subcommand() {
+            (_, Some(sub)) if sub.occurrences_of("error-format") > 0 => sub,
+            _ => matches,
+        };
+        args.value_of("error-format") == Some("json")
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = cli::mk().get_matches();
+    let matches = cli::mk().get_matches();
+    let json = cli::json_errors(&matches);
+    if matches.subcommand_matches("repl").is_some() {
+        return Ok(repl::Repl::new(json).run()?);
+    }
+
+    let (args, run) = match matches.subcommand_matches("run") {
+        Some(run_args) => (run_args, true),
+        None => (&matches, false),
+    };
 
     let file: PathBuf = args
         .value_of_lossy("file")
@@ -35,18 +79,55 @@ fn main() -> anyhow::Result<()> {
     let file =
         fs::read_to_string(&file).context(anyhow!("File doesn't exist: {}", file.display()))?;
 
-    let ast = parser::parse(&file);
+    let ast = parser::parse(&file)
+        .unwrap_or_else(|errors| report_errors(&file, json, &errors, ParsingError::diagnostic));
+
+    if args.is_present("dump-ast") {
+        println!("{:#?}", ast);
+    }
+
+    let res = resolve::resolve_unit(&ast)
+        .unwrap_or_else(|errors| report_errors(&file, json, &errors, ParsingError::diagnostic));
+    let tast = lower::lower_unit(&ast, &res)
+        .unwrap_or_else(|errors| report_errors(&file, json, &errors, ParsingError::diagnostic));
+
+    let tast = typeck::infer_unit(&tast)
+        .unwrap_or_else(|errors| report_errors(&file, json, &errors, TypeError::diagnostic));
 
-    if let Err(errors) = ast {
-        for err in errors {
-            eprintln!("{}", parser::WithCode::new(&file, &err));
+    if args.is_present("dump-tast") {
+        for ex in &tast {
+            println!("{}", ex);
         }
-        std::process::exit(1);
     }
 
-    if args.is_present("dump-ast") {
-        println!("{:#?}", ast);
+    if run {
+        let use_vm = args.value_of("backend") == Some("vm");
+        let mut interp = interp::Interp::new();
+        let mut vm = vm::Vm::new();
+        for ex in &tast {
+            let result = if use_vm {
+                vm.eval_top(ex)
+            } else {
+                interp.eval_top(ex)
+            };
+            match result {
+                Ok(Some(val)) => println!("{}", val),
+                Ok(None) => {}
+                Err(e) => report_errors(&file, json, &[e], RuntimeError::diagnostic),
+            }
+        }
     }
 
     Ok(())
 }
+
+fn report_errors<T>(code: &str, json: bool, errors: &[T], diagnostic: fn(&T) -> Diagnostic) -> ! {
+    for err in errors.iter().map(diagnostic) {
+        if json {
+            eprintln!("{}", err.to_json());
+        } else {
+            eprintln!("{}", WithCode::new(code, &err));
+        }
+    }
+    std::process::exit(1);
+}
@@ -1,32 +1,37 @@
+use crate::diagnostic::{Diagnostic, Label};
 use std::fmt::Display;
 use std::fmt::{self, Debug};
 use std::sync::Arc;
 use tree_sitter::Point;
-use tree_sitter::{Language, Node, Parser, Range, Tree};
+use tree_sitter::{InputEdit, Language, Node, Parser, Range, Tree};
 
 extern "C" {
     fn tree_sitter_fang() -> Language;
 }
 
-pub fn parse_tree(code: &str) -> Tree {
+fn new_parser() -> Parser {
     let mut parser = Parser::new();
     let language = unsafe { tree_sitter_fang() };
     parser.set_language(language).unwrap();
+    parser
+}
 
-    let tree = parser.parse(code, None).unwrap();
-    tree
+pub(crate) fn parse_tree(code: &str) -> Tree {
+    new_parser().parse(code, None).unwrap()
 }
 
 #[derive(Debug)]
 pub struct ParsingError {
     msg: String,
-    loc: Loc,
-    offset: usize,
+    span: SrcSpan,
+    /// Other parts of the code involved, e.g. where the construct that failed
+    /// to parse started.
+    labels: Vec<Label>,
 }
 
 pub struct WithCode<'a, 'b: 'a, T> {
-    code: &'a str,
-    t: &'b T,
+    pub(crate) code: &'a str,
+    pub(crate) t: &'b T,
 }
 
 impl<'a, 'b, T> WithCode<'a, 'b, T> {
@@ -39,100 +44,238 @@ impl ParsingError {
     fn new(msg: String, range: Range) -> ParsingError {
         ParsingError {
             msg,
-            loc: range.start_point.into(),
-            offset: range.start_byte,
+            span: range.into(),
+            labels: vec![],
+        }
+    }
+
+    pub(crate) fn at<T>(msg: String, node: &N<T>) -> ParsingError {
+        ParsingError {
+            msg,
+            span: node.src_span(),
+            labels: vec![],
         }
     }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let d = Diagnostic::error(&self.msg)
+            .with_code("parse")
+            .at(Some(self.span));
+        self.labels.iter().cloned().fold(d, Diagnostic::with_label)
+    }
 }
 
 impl<'a, 'b> Display for WithCode<'a, 'b, ParsingError> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::result::Result<(), fmt::Error> {
-        let prev_newline = self.code[..self.t.offset].rfind("\n");
-        let next_newline = self.code[self.t.offset..]
-            .find("\n")
-            .map(|pos| pos + self.t.offset);
-
-        let line = match (prev_newline, next_newline) {
-            (Some(p), Some(n)) => self.code[p..n].trim(),
-            (Some(p), None) => self.code[p..].trim(),
-            (None, Some(n)) => self.code[..n].trim(),
-            (None, None) => self.code.trim(),
-        };
-
-        let row_num_width = (self.t.loc.row + 1).to_string().len();
-        let fill = row_num_width + 3;
-
-        writeln!(
-            f,
-            "{:>fill$} {}:{}",
-            "-->",
-            self.t.loc.row + 1,
-            self.t.loc.col + 1,
-            fill = fill
-        )?;
-        writeln!(
-            f,
-            " {:>fill$} | {}",
-            self.t.loc.row + 1,
-            line,
-            fill = row_num_width
-        )?;
-        writeln!(f, "{:>fill$} ^", " ", fill = fill + self.t.loc.col)?;
-        writeln!(
-            f,
-            "{:>fill$} {}",
-            " ",
-            self.t.msg,
-            fill = fill + self.t.loc.col
-        )
+        write!(f, "{}", WithCode::new(self.code, &self.t.diagnostic()))
     }
 }
 
 type Result<A> = std::result::Result<A, Vec<ParsingError>>;
 
 pub fn parse(code: &str) -> Result<CompilationUnit> {
-    let tree = parse_tree(code);
+    let (unit, errors) = parse_recovering(code);
+    if errors.is_empty() {
+        Ok(unit)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses as much of the code as possible. Code that doesn't parse becomes
+/// `Ex::Error` nodes, so the unit is complete only if there are no errors.
+pub fn parse_recovering(code: &str) -> (CompilationUnit, Vec<ParsingError>) {
+    unit_of_tree(code, &parse_tree(code))
+}
+
+fn unit_of_tree(code: &str, tree: &Tree) -> (CompilationUnit, Vec<ParsingError>) {
     let root = tree.root_node();
-    let mut errors = vec![];
-    let mut expressions = vec![];
+    let item = Item { root, start: 0 };
+    let mut errors = collect_error_nodes(code, root, item);
 
-    let grammar_errors = collect_error_nodes(code, root);
+    let nodes = root
+        .named_children(&mut root.walk())
+        .map(|child| parse_ex(code, child, &mut errors))
+        .collect();
 
-    if grammar_errors.is_empty() {
-        for child in root.named_children(&mut root.walk()) {
-            match parse_ex(code, child) {
-                Ok(ex) => expressions.push(ex),
-                Err(e) => errors.extend(e),
+    (CompilationUnit { nodes }, errors)
+}
+
+/// Replaces the bytes from `start` to `end` of the code with `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// How the top-level nodes of a unit changed with an edit.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// Indices of the new nodes whose code is new or changed.
+    pub changed: Vec<usize>,
+    /// Old and new indices of the nodes whose code is the same, though it may
+    /// have moved. Old nodes that are in neither list were removed.
+    pub unchanged: Vec<(usize, usize)>,
+}
+
+/// Code being edited, e.g. in an editor. Each edit reparses only the part of
+/// the syntax tree it affects.
+pub struct ParseSession {
+    parser: Parser,
+    code: String,
+    tree: Tree,
+    unit: CompilationUnit,
+    errors: Vec<ParsingError>,
+}
+
+impl ParseSession {
+    pub fn new(code: &str) -> ParseSession {
+        let mut parser = new_parser();
+        let tree = parser.parse(code, None).unwrap();
+        let (unit, errors) = unit_of_tree(code, &tree);
+        ParseSession {
+            parser,
+            code: code.to_string(),
+            tree,
+            unit,
+            errors,
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// The unit as of the last edit, with `Ex::Error` where it doesn't parse.
+    pub fn unit(&self) -> &CompilationUnit {
+        &self.unit
+    }
+
+    pub fn errors(&self) -> &[ParsingError] {
+        &self.errors
+    }
+
+    /// Applies the edit and reparses the code.
+    ///
+    /// Panics if the edited range is out of the code's bounds or doesn't lie
+    /// on char boundaries.
+    pub fn edit(&mut self, edit: &Edit) -> Changes {
+        let old_code = self.code.clone();
+        let old_spans: Vec<_> = self
+            .unit
+            .nodes
+            .iter()
+            .map(|n| n.src_span().offset)
+            .collect();
+
+        let start_position = point_at(&self.code, edit.start);
+        let old_end_position = point_at(&self.code, edit.end);
+        self.code.replace_range(edit.start..edit.end, &edit.text);
+        let new_end = edit.start + edit.text.len();
+        self.tree.edit(&InputEdit {
+            start_byte: edit.start,
+            old_end_byte: edit.end,
+            new_end_byte: new_end,
+            start_position,
+            old_end_position,
+            new_end_position: point_at(&self.code, new_end),
+        });
+
+        let tree = self.parser.parse(&self.code, Some(&self.tree)).unwrap();
+        let restructured: Vec<Span<usize>> =
+            self.tree.changed_ranges(&tree).map(Span::from).collect();
+
+        let (unit, errors) = unit_of_tree(&self.code, &tree);
+        let mut changes = Changes::default();
+        for (idx, node) in unit.nodes.iter().enumerate() {
+            let span = node.src_span().offset;
+            // A node is the same if an old node had its text at the same place,
+            // before shifting by the edit, and the tree around it didn't change.
+            let shift = |offset: usize| {
+                if offset >= new_end {
+                    offset + edit.end - new_end
+                } else {
+                    offset
+                }
+            };
+            let (old_start, old_end) = (shift(span.start), shift(span.end));
+            let text = &self.code[span.start..span.end];
+            let same = old_spans.iter().position(|old| {
+                old.start == old_start
+                    && old.end == old_end
+                    && old_code.get(old.start..old.end) == Some(text)
+            });
+            let is_restructured = restructured
+                .iter()
+                .any(|r| r.start < span.end && span.start < r.end);
+            match same {
+                Some(old_idx) if !is_restructured => changes.unchanged.push((old_idx, idx)),
+                _ => changes.changed.push(idx),
             }
         }
-    } else {
-        errors.extend(grammar_errors);
+
+        self.tree = tree;
+        self.unit = unit;
+        self.errors = errors;
+        changes
     }
+}
 
-    if errors.is_empty() {
-        Ok(CompilationUnit { nodes: expressions })
-    } else {
-        Err(errors)
+/// Row and byte column of a byte offset.
+fn point_at(code: &str, offset: usize) -> Point {
+    let before = &code[..offset];
+    let row = before.matches('\n').count();
+    let column = before.rfind('\n').map_or(offset, |nl| offset - nl - 1);
+    Point::new(row, column)
+}
+
+/// Whether parsing fails only because the code ends too early, e.g. after
+/// `let a = 1 in` or in the middle of an `if`. A misplaced token right at the
+/// end looks the same, so callers should allow forcing the input through.
+pub fn is_incomplete(code: &str) -> bool {
+    fn ends_early(node: Node<'_>, end: usize) -> bool {
+        node.is_missing()
+            || (node.is_error() && node.end_byte() >= end)
+            || node
+                .children(&mut node.walk())
+                .any(|child| ends_early(child, end))
     }
+
+    let tree = parse_tree(code);
+    ends_early(tree.root_node(), code.trim_end().len())
 }
 
-fn collect_error_nodes(code: &str, node: Node<'_>) -> Vec<ParsingError> {
+fn collect_error_nodes(code: &str, node: Node<'_>, item: Item<'_>) -> Vec<ParsingError> {
     // Sometimes ERROR node can have complex structure inside with additional
     // ERROR nodes. Therefore we first try to get the more specific issue found
     // inside child nodes, and if nothing found return a more generic error from
     // current node.
     let this_nodes_error = if node.is_error() {
-        let error = ParsingError {
-            msg: format!("Unexpected token"),
-            loc: node.range().start_point.into(),
-            offset: node.range().start_byte,
+        let end = Range {
+            start_byte: node.end_byte(),
+            end_byte: node.end_byte(),
+            start_point: node.end_position(),
+            end_point: node.end_position(),
+        };
+        let error = match innermost_open(code, item, node.end_byte()) {
+            // The right-hand side of a binding followed by another binding.
+            _ if is_missing_comma(node) => {
+                ParsingError::new("Expected `,` or `in`".to_string(), end)
+            }
+            // The tokens of the construct may go on until the end of the error.
+            Some(open) if open.opener.start_byte() >= node.start_byte() => open.error(end),
+            Some(open) => open.error(node.range()),
+            None => ParsingError::new("Unexpected token".to_string(), node.range()),
         };
         vec![error]
     } else if node.is_missing() {
-        let error = ParsingError {
-            msg: format!("Missing token"),
-            loc: node.range().start_point.into(),
-            offset: node.range().start_byte,
+        let error = match innermost_open(code, item, node.start_byte()) {
+            Some(open) if open.expected == node.kind() => open.error(node.range()),
+            _ if node.is_named() => {
+                ParsingError::new("Expected an expression".to_string(), node.range())
+            }
+            _ => ParsingError::new(format!("Expected `{}`", node.kind()), node.range()),
         };
         vec![error]
     } else {
@@ -140,9 +283,14 @@ fn collect_error_nodes(code: &str, node: Node<'_>) -> Vec<ParsingError> {
     };
 
     let mut all_child_errors = vec![];
-    node.children(&mut node.walk())
-        .map(|child| collect_error_nodes(code, child))
-        .for_each(|child_errors| all_child_errors.extend(child_errors));
+    let mut item = item;
+    for child in node.children(&mut node.walk()) {
+        all_child_errors.extend(collect_error_nodes(code, child, item));
+        // Top-level items are the context of the errors inside them.
+        if node.parent().is_none() && child.kind() == "\n" {
+            item.start = child.end_byte();
+        }
+    }
 
     if all_child_errors.is_empty() {
         this_nodes_error
@@ -151,34 +299,161 @@ fn collect_error_nodes(code: &str, node: Node<'_>) -> Vec<ParsingError> {
     }
 }
 
-pub fn parse_ex(code: &str, node: Node<'_>) -> Result<Ex> {
-    if node.is_error() {
-        panic!("Not implemented for error node:\n{:#?}", node);
+/// Whether the error is the right-hand side of a binding that the parser
+/// nested the next binding into, because the `,` between them is missing.
+fn is_missing_comma(node: Node<'_>) -> bool {
+    node.parent().is_some_and(|p| p.kind() == "binding")
+        && node.next_sibling().is_some_and(|n| n.kind() == "binding")
+}
+
+/// The tokens of the top-level item that starts at `start`.
+#[derive(Clone, Copy)]
+struct Item<'t> {
+    root: Node<'t>,
+    start: usize,
+}
+
+/// A construct whose tokens started but didn't finish.
+struct Open<'t> {
+    opener: Node<'t>,
+    /// The next keyword of the construct.
+    expected: &'static str,
+}
+
+impl<'t> Open<'t> {
+    fn construct(&self) -> &'static str {
+        match self.expected {
+            "in" => "`let`",
+            "then" | "else" => "`if`",
+            "->" => "lambda",
+            _ => "`(`",
+        }
     }
 
-    if node.is_missing() {
-        panic!("Not implemented for missing node:\n{:#?}", node);
+    fn error(&self, range: Range) -> ParsingError {
+        let start: Loc = self.opener.start_position().into();
+        let verb = if self.expected == "then" {
+            "continue"
+        } else {
+            "close"
+        };
+        let msg = format!(
+            "Expected `{}` to {} {} started at {}:{}",
+            self.expected,
+            verb,
+            self.construct(),
+            start.row + 1,
+            start.col + 1
+        );
+        let label = Label::new(
+            self.opener.range().into(),
+            format!("{} starts here", self.construct()),
+        );
+        ParsingError {
+            labels: vec![label],
+            ..ParsingError::new(msg, range)
+        }
+    }
+}
+
+/// Follows the keywords of the tokens of the item that end by `end` and
+/// returns the innermost construct left open. Keywords are matched by their
+/// text, since the parser may take them for identifiers when recovering.
+fn innermost_open<'t>(code: &str, item: Item<'t>, end: usize) -> Option<Open<'t>> {
+    fn tokens<'t>(node: Node<'t>, bytes: &std::ops::Range<usize>, out: &mut Vec<Node<'t>>) {
+        if node.end_byte() <= bytes.start || node.start_byte() >= bytes.end || node.is_missing() {
+            return;
+        }
+        if node.child_count() == 0 {
+            if node.end_byte() <= bytes.end {
+                out.push(node);
+            }
+            return;
+        }
+        for child in node.children(&mut node.walk()) {
+            tokens(child, bytes, out);
+        }
+    }
+
+    let mut toks = vec![];
+    tokens(item.root, &(item.start..end), &mut toks);
+
+    let mut open: Vec<Open<'t>> = vec![];
+    for tok in toks {
+        let expected = match &code[tok.byte_range()] {
+            "let" => Some("in"),
+            "if" => Some("then"),
+            "\\" => Some("->"),
+            "(" => Some(")"),
+            closer => {
+                // A keyword closes only the innermost construct, so that the
+                // ones it skips are reported.
+                if open.last().is_some_and(|o| o.expected == closer) {
+                    let closed = open.pop().unwrap();
+                    if closer == "then" {
+                        open.push(Open {
+                            expected: "else",
+                            ..closed
+                        });
+                    }
+                }
+                None
+            }
+        };
+        if let Some(expected) = expected {
+            open.push(Open {
+                opener: tok,
+                expected,
+            });
+        }
+    }
+    open.pop()
+}
+
+/// Parses the expression, replacing the parts that don't parse by
+/// `Ex::Error`. Errors of the grammar are expected to be collected by
+/// `collect_error_nodes`, only the other ones are added to `errors`.
+pub(crate) fn parse_ex(code: &str, node: Node<'_>, errors: &mut Vec<ParsingError>) -> Ex {
+    if node.is_error() || node.is_missing() {
+        return error_ex(node.range());
     }
 
     match node.kind() {
-        "const_int" => parse_int(code, node.range()).map(|x| x.into()),
-        "const_bool" => Ok(parse_bool(code, node.range()).into()),
-        "identifier" => Ok(parse_identifier(code, node.range()).into()),
-        "infix_ex" => parse_infix_ex(code, node).map(|x| x.into()),
-        "prefix_ex" => parse_prefix_ex(code, node).map(|x| x.into()),
-        "binding" => parse_binding(code, node).map(|x| x.into()),
-        "let" => parse_let(code, node).map(|x| x.into()),
-        "lambda" => parse_lambda(code, node).map(|x| x.into()),
-        "ap" => parse_ap(code, node).map(|x| x.into()),
-        "cond" => parse_cond(code, node).map(|x| x.into()),
+        "const_int" => match parse_int(code, node.range()) {
+            Ok(n) => n.into(),
+            Err(e) => {
+                errors.extend(e);
+                error_ex(node.range())
+            }
+        },
+        "const_bool" => parse_bool(code, node.range()).into(),
+        "identifier" => parse_identifier(code, node.range()).into(),
+        "infix_ex" => {
+            parse_infix_ex(code, node, errors).map_or_else(|| error_ex(node.range()), Ex::from)
+        }
+        "prefix_ex" => {
+            parse_prefix_ex(code, node, errors).map_or_else(|| error_ex(node.range()), Ex::from)
+        }
+        "binding" => {
+            parse_binding(code, node, errors).map_or_else(|| error_ex(node.range()), Ex::from)
+        }
+        "let" => parse_let(code, node, errors).into(),
+        "lambda" => parse_lambda(code, node, errors).into(),
+        "ap" => parse_ap(code, node, errors).into(),
+        "cond" => parse_cond(code, node, errors).into(),
         other => {
-            let err_msg = format!("Not implemented for node: {}", other);
-            panic!(err_msg)
+            let msg = format!("Unsupported syntax: {}", other);
+            errors.push(ParsingError::new(msg, node.range()));
+            error_ex(node.range())
         }
     }
 }
 
-pub fn parse_int(code: &str, range: Range) -> Result<N<i64>> {
+fn error_ex(range: Range) -> Ex {
+    Ex::Error(N::new((), range))
+}
+
+pub(crate) fn parse_int(code: &str, range: Range) -> Result<N<i64>> {
     let int_str: String = code[range.start_byte..range.end_byte].to_string();
     let int_val = int_str.replace("_", "").parse::<i64>();
     match int_val {
@@ -187,129 +462,124 @@ pub fn parse_int(code: &str, range: Range) -> Result<N<i64>> {
     }
 }
 
-pub fn parse_bool(code: &str, range: Range) -> N<bool> {
+pub(crate) fn parse_bool(code: &str, range: Range) -> N<bool> {
     let bool_str = &code[range.start_byte..range.end_byte];
     match bool_str {
-        "True" => N::new(true, range).into(),
-        "False" => N::new(false, range).into(),
+        "True" => N::new(true, range),
+        "False" => N::new(false, range),
         other => panic!("Unexpected bool const: {}", other),
     }
 }
 
-pub fn parse_identifier(code: &str, range: Range) -> N<Ident> {
+pub(crate) fn parse_identifier(code: &str, range: Range) -> N<Ident> {
     let ident_name = &code[range.start_byte..range.end_byte];
     N::new(Ident(ident_name.to_string()), range)
 }
 
-pub fn parse_infix_ex(code: &str, node: Node<'_>) -> Result<N<InfixEx>> {
-    let op_node = require_child_by_field_name(node, "op");
-    let lhs_node = require_child_by_field_name(node, "lhs");
-    let rhs_node = require_child_by_field_name(node, "rhs");
-
+pub(crate) fn parse_infix_ex(
+    code: &str,
+    node: Node<'_>,
+    errors: &mut Vec<ParsingError>,
+) -> Option<N<InfixEx>> {
+    let op_node = child_by_field_name(node, "op", errors)?;
     let op = parse_op(code, op_node.range());
-    let lhs = parse_ex(code, lhs_node);
-    let rhs = parse_ex(code, rhs_node);
-
-    combine_results_2(lhs, rhs).map(|(lhs, rhs)| {
-        N::new(
-            InfixEx {
-                op: op,
-                lhs: lhs,
-                rhs: rhs,
-            },
-            node.range(),
-        )
-    })
-}
-
-pub fn parse_prefix_ex(code: &str, node: Node<'_>) -> Result<N<PrefixEx>> {
-    let op_node = require_child_by_field_name(node, "op");
-    let body_node = require_child_by_field_name(node, "body");
+    let lhs = parse_field(code, node, "lhs", errors);
+    let rhs = parse_field(code, node, "rhs", errors);
+    Some(N::new(InfixEx { op, lhs, rhs }, node.range()))
+}
+
+pub(crate) fn parse_prefix_ex(
+    code: &str,
+    node: Node<'_>,
+    errors: &mut Vec<ParsingError>,
+) -> Option<N<PrefixEx>> {
+    let op_node = child_by_field_name(node, "op", errors)?;
     let op = parse_op(code, op_node.range());
-    let body = parse_ex(code, body_node)?;
-    Ok(N::new(PrefixEx { op, body }, node.range()))
+    let body = parse_field(code, node, "body", errors);
+    Some(N::new(PrefixEx { op, body }, node.range()))
 }
 
-pub fn parse_let(code: &str, node: Node<'_>) -> Result<N<LetEx>> {
-    let bindings: Vec<_> = node
+/// Bindings that lack a name are left out.
+pub(crate) fn parse_let(code: &str, node: Node<'_>, errors: &mut Vec<ParsingError>) -> N<LetEx> {
+    let bindings = node
         .children_by_field_name("bindings", &mut node.walk())
         .filter(|n| n.is_named())
-        .map(|n| parse_binding(code, n))
+        .filter_map(|n| parse_binding(code, n, errors))
         .collect();
-    let bindings = combine_results_n(bindings);
-    let body_node = require_child_by_field_name(node, "body");
-    let body = parse_ex(code, body_node);
-
-    combine_results_2(bindings, body)
-        .map(|(bindings, body)| N::new(LetEx { bindings, body }, node.range()))
+    let body = parse_field(code, node, "body", errors);
+    N::new(LetEx { bindings, body }, node.range())
 }
 
-pub fn parse_binding(code: &str, node: Node<'_>) -> Result<N<Bind>> {
-    let lhs_node = require_child_by_field_name(node, "lhs");
+pub(crate) fn parse_binding(
+    code: &str,
+    node: Node<'_>,
+    errors: &mut Vec<ParsingError>,
+) -> Option<N<Bind>> {
+    let lhs_node = child_by_field_name(node, "lhs", errors)?;
     let lhs = parse_identifier(code, lhs_node.range());
+    let params = parse_params(code, node);
+    let rhs = parse_field(code, node, "rhs", errors);
+    Some(N::new(Bind { lhs, params, rhs }, node.range()))
+}
 
-    let params: Vec<_> = node
-        .children_by_field_name("params", &mut node.walk())
-        .filter(|n| n.is_named())
-        .map(|n| parse_identifier(code, n.range()))
-        .collect();
-
-    let rhs_node = require_child_by_field_name(node, "rhs");
-    let rhs = parse_ex(code, rhs_node)?;
-
-    Ok(N::new(Bind { lhs, params, rhs }, node.range()))
+pub(crate) fn parse_lambda(code: &str, node: Node<'_>, errors: &mut Vec<ParsingError>) -> N<Lam> {
+    let params = parse_params(code, node);
+    let body = parse_field(code, node, "body", errors);
+    N::new(Lam { params, body }, node.range())
 }
 
-pub fn parse_lambda(code: &str, node: Node<'_>) -> Result<N<Lam>> {
-    let params: Vec<_> = node
-        .children_by_field_name("params", &mut node.walk())
-        .filter(|n| n.is_named())
+fn parse_params(code: &str, node: Node<'_>) -> Vec<N<Ident>> {
+    node.children_by_field_name("params", &mut node.walk())
+        .filter(|n| n.is_named() && !n.is_missing() && !n.is_error())
         .map(|n| parse_identifier(code, n.range()))
-        .collect();
-    let body_node = require_child_by_field_name(node, "body");
-    let body = parse_ex(code, body_node)?;
-    Ok(N::new(Lam { params, body }, node.range()))
+        .collect()
 }
 
-pub fn parse_ap(code: &str, node: Node<'_>) -> Result<N<Ap>> {
-    let receiver_node = require_child_by_field_name(node, "receiver");
-    let receiver = parse_ex(code, receiver_node);
-    let arguments: Vec<_> = node
+pub(crate) fn parse_ap(code: &str, node: Node<'_>, errors: &mut Vec<ParsingError>) -> N<Ap> {
+    let receiver = parse_field(code, node, "receiver", errors);
+    let args = node
         .children_by_field_name("arguments", &mut node.walk())
         .filter(|n| n.is_named())
-        .map(|n| parse_ex(code, n))
+        .map(|n| parse_ex(code, n, errors))
         .collect();
-    let arguments = combine_results_n(arguments);
-
-    combine_results_2(receiver, arguments)
-        .map(|(receiver, args)| N::new(Ap { receiver, args }, node.range()))
+    N::new(Ap { receiver, args }, node.range())
 }
 
-pub fn parse_cond(code: &str, node: Node<'_>) -> Result<N<Cond>> {
-    let pred_node = require_child_by_field_name(node, "pred");
-    let then_node = require_child_by_field_name(node, "then");
-    let els_node = require_child_by_field_name(node, "else");
-
-    let pred = parse_ex(code, pred_node);
-    let then = parse_ex(code, then_node);
-    let els = parse_ex(code, els_node);
+pub(crate) fn parse_cond(code: &str, node: Node<'_>, errors: &mut Vec<ParsingError>) -> N<Cond> {
+    let pred = parse_field(code, node, "pred", errors);
+    let then = parse_field(code, node, "then", errors);
+    let els = parse_field(code, node, "else", errors);
+    N::new(Cond { pred, then, els }, node.range())
+}
 
-    combine_results_3(pred, then, els)
-        .map(|(pred, then, els)| N::new(Cond { pred, then, els }, node.range()))
+/// The expression in the field, or an error in its place if there is none.
+fn parse_field(code: &str, node: Node<'_>, field: &str, errors: &mut Vec<ParsingError>) -> Ex {
+    match child_by_field_name(node, field, errors) {
+        Some(child) => parse_ex(code, child, errors),
+        None => error_ex(node.range()),
+    }
 }
 
-pub fn require_child_by_field_name<'tree>(node: Node<'tree>, field: &str) -> Node<'tree> {
-    node.child_by_field_name(field).unwrap_or_else(|| {
-        panic!(
-            "Required field {} is missing in node {}:\n{}",
-            field,
-            node.kind(),
-            node.to_sexp()
-        )
-    })
+/// A missing field is reported unless the node already has a syntax error,
+/// which explains it.
+fn child_by_field_name<'tree>(
+    node: Node<'tree>,
+    field: &str,
+    errors: &mut Vec<ParsingError>,
+) -> Option<Node<'tree>> {
+    // A parenthesized expression puts its `(` and `)` under the same field as
+    // the expression itself, so we pick the first named child.
+    let child = node
+        .children_by_field_name(field, &mut node.walk())
+        .find(|n| n.is_named());
+    if child.is_none() && !node.has_error() {
+        let msg = format!("Missing {} of {}", field, node.kind());
+        errors.push(ParsingError::new(msg, node.range()));
+    }
+    child
 }
 
-pub fn combine_results_2<A, B, E>(
+pub(crate) fn combine_results_2<A, B, E>(
     a: std::result::Result<A, Vec<E>>,
     b: std::result::Result<B, Vec<E>>,
 ) -> std::result::Result<(A, B), Vec<E>> {
@@ -324,7 +594,7 @@ pub fn combine_results_2<A, B, E>(
     }
 }
 
-pub fn combine_results_3<A, B, C, E>(
+pub(crate) fn combine_results_3<A, B, C, E>(
     a: std::result::Result<A, Vec<E>>,
     b: std::result::Result<B, Vec<E>>,
     c: std::result::Result<C, Vec<E>>,
@@ -335,7 +605,7 @@ pub fn combine_results_3<A, B, C, E>(
     }
 }
 
-pub fn combine_results_n<A, E>(
+pub(crate) fn combine_results_n<A, E>(
     results: Vec<std::result::Result<A, Vec<E>>>,
 ) -> std::result::Result<Vec<A>, Vec<E>> {
     let mut errs = vec![];
@@ -355,16 +625,16 @@ pub fn combine_results_n<A, E>(
     }
 }
 
-pub fn parse_op(code: &str, range: Range) -> N<Operator> {
+pub(crate) fn parse_op(code: &str, range: Range) -> N<Operator> {
     let op_str = &code[range.start_byte..range.end_byte];
     let op = Operator(op_str.to_string());
     N::new(op, range)
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Loc {
-    row: usize,
-    col: usize,
+    pub(crate) row: usize,
+    pub(crate) col: usize,
 }
 
 impl From<Point> for Loc {
@@ -376,10 +646,10 @@ impl From<Point> for Loc {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Span<T> {
-    start: T,
-    end: T,
+    pub(crate) start: T,
+    pub(crate) end: T,
 }
 
 impl From<Range> for Span<usize> {
@@ -400,16 +670,83 @@ impl From<Range> for Span<Loc> {
     }
 }
 
+/// Which of several pieces of code a span is in, e.g. which input of a REPL.
+/// The parser puts spans in the default source, `tast::Ex::in_source` moves
+/// them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SourceId(pub u32);
+
+/// The code of several sources, e.g. of the inputs of a REPL.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    codes: Vec<String>,
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        Sources::default()
+    }
+
+    /// Keeps the code and returns its source. The default source isn't one of
+    /// them, it's the source of code that wasn't added.
+    pub fn add(&mut self, code: &str) -> SourceId {
+        self.codes.push(code.to_string());
+        SourceId(self.codes.len() as u32)
+    }
+
+    pub fn get(&self, source: SourceId) -> Option<&str> {
+        let idx = (source.0 as usize).checked_sub(1)?;
+        self.codes.get(idx).map(|code| code.as_str())
+    }
+}
+
+/// Location of a node in the source code, both as byte offsets and as
+/// row/column pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SrcSpan {
+    pub(crate) offset: Span<usize>,
+    pub(crate) loc: Span<Loc>,
+    pub(crate) source: SourceId,
+}
+
+impl From<Range> for SrcSpan {
+    fn from(r: Range) -> Self {
+        Self {
+            offset: r.into(),
+            loc: r.into(),
+            source: SourceId::default(),
+        }
+    }
+}
+
+impl SrcSpan {
+    /// The same location in the given source.
+    pub fn in_source(self, source: SourceId) -> SrcSpan {
+        SrcSpan { source, ..self }
+    }
+
+    pub fn source(&self) -> SourceId {
+        self.source
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct CompilationUnit {
-    nodes: Vec<Ex>,
+    pub(crate) nodes: Vec<Ex>,
+}
+
+impl CompilationUnit {
+    /// The top-level expressions, in the order of the code.
+    pub fn nodes(&self) -> &[Ex] {
+        &self.nodes
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct N<T> {
-    t: Arc<T>,
-    offset_span: Span<usize>,
-    loc_span: Span<Loc>,
+    pub(crate) t: Arc<T>,
+    pub(crate) offset_span: Span<usize>,
+    pub(crate) loc_span: Span<Loc>,
 }
 
 impl<T> N<T> {
@@ -420,6 +757,18 @@ impl<T> N<T> {
             loc_span: range.into(),
         }
     }
+
+    pub fn t(&self) -> &T {
+        &self.t
+    }
+
+    pub(crate) fn src_span(&self) -> SrcSpan {
+        SrcSpan {
+            offset: self.offset_span,
+            loc: self.loc_span,
+            source: SourceId::default(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -430,47 +779,47 @@ pub struct Operator(pub String);
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct InfixEx {
-    op: N<Operator>,
-    lhs: Ex,
-    rhs: Ex,
+    pub(crate) op: N<Operator>,
+    pub(crate) lhs: Ex,
+    pub(crate) rhs: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct PrefixEx {
-    op: N<Operator>,
-    body: Ex,
+    pub(crate) op: N<Operator>,
+    pub(crate) body: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct LetEx {
-    bindings: Vec<N<Bind>>,
-    body: Ex,
+    pub(crate) bindings: Vec<N<Bind>>,
+    pub(crate) body: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Bind {
-    lhs: N<Ident>,
-    params: Vec<N<Ident>>,
-    rhs: Ex,
+    pub(crate) lhs: N<Ident>,
+    pub(crate) params: Vec<N<Ident>>,
+    pub(crate) rhs: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Lam {
-    params: Vec<N<Ident>>,
-    body: Ex,
+    pub(crate) params: Vec<N<Ident>>,
+    pub(crate) body: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Ap {
-    receiver: Ex,
-    args: Vec<Ex>,
+    pub(crate) receiver: Ex,
+    pub(crate) args: Vec<Ex>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Cond {
-    pred: Ex,
-    then: Ex,
-    els: Ex,
+    pub(crate) pred: Ex,
+    pub(crate) then: Ex,
+    pub(crate) els: Ex,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -485,6 +834,26 @@ pub enum Ex {
     Lambda(N<Lam>),
     Let(N<LetEx>),
     Prefix(N<PrefixEx>),
+    /// Code that doesn't parse, in place of the expression expected there.
+    Error(N<()>),
+}
+
+impl Ex {
+    pub fn src_span(&self) -> SrcSpan {
+        match self {
+            Ex::Application(n) => n.src_span(),
+            Ex::Binding(n) => n.src_span(),
+            Ex::Condition(n) => n.src_span(),
+            Ex::ConstBool(n) => n.src_span(),
+            Ex::ConstInt(n) => n.src_span(),
+            Ex::Identifier(n) => n.src_span(),
+            Ex::Infix(n) => n.src_span(),
+            Ex::Lambda(n) => n.src_span(),
+            Ex::Let(n) => n.src_span(),
+            Ex::Prefix(n) => n.src_span(),
+            Ex::Error(n) => n.src_span(),
+        }
+    }
 }
 
 impl From<N<PrefixEx>> for Ex {
@@ -552,17 +921,126 @@ mod test {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    fn parse_single(code: &str) -> Ex {
+        let mut r = parse(code).unwrap();
+        assert_eq!(r.nodes.len(), 1);
+        r.nodes.remove(0)
+    }
+
     #[test]
     fn t_parse_int() {
-        let r = parse("a + b * c").unwrap();
-        assert_eq!(r, CompilationUnit { nodes: vec![] });
+        // A whole expression parses to a single node.
+        assert!(matches!(parse_single("a + b * c"), Ex::Infix(_)));
 
-        // let r = parse("1_000_000").unwrap();
-        // assert_eq!(format!("{:#?}", r), "1000000");
+        match parse_single("1_000_000") {
+            Ex::ConstInt(n) => assert_eq!(*n.t, 1_000_000),
+            other => panic!("Expected an int, got: {:?}", other),
+        }
+
+        assert!(parse("9_999_999_999_999_999_999").is_err());
+    }
 
-        // let r = parse("9_999_999_999_999_999_999").unwrap();
-        // assert_eq!(format!("{:#?}", r), "1");
-        // let r = parse("_-").unwrap();
-        // assert_eq!(format!("{:#?}", r), "1");
+    #[test]
+    fn t_parse_recovering() {
+        let (unit, errors) = parse_recovering("x = 99_999_999_999_999_999_999\ny = 1");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(unit.nodes.len(), 2);
+        match &unit.nodes[0] {
+            Ex::Binding(b) => assert!(matches!(b.t.rhs, Ex::Error(_))),
+            other => panic!("Expected a binding, got: {:?}", other),
+        }
+
+        let (unit, errors) = parse_recovering("1 ) + 2\n3");
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(unit.nodes[1], Ex::ConstInt(_)));
+
+        let (unit, errors) = parse_recovering("if 1 then\n5");
+        assert!(!errors.is_empty());
+        let span = unit.nodes[0].src_span().offset;
+        assert!(matches!(unit.nodes[0], Ex::Error(_)));
+        assert_eq!((span.start, span.end), (0, 11));
+    }
+
+    #[test]
+    fn t_expected_token_errors() {
+        let errors = parse("x = 1\nlet a = 1\n    a + b").unwrap_err();
+        assert_eq!(errors[0].msg, "Expected `in` to close `let` started at 2:1");
+        assert_eq!(errors[0].span.offset.start, 14);
+        let label = &errors[0].labels[0];
+        assert_eq!(label.msg, "`let` starts here");
+        assert_eq!((label.span.offset.start, label.span.offset.end), (6, 9));
+
+        let errors = parse("f(1, 2").unwrap_err();
+        assert_eq!(errors[0].msg, "Expected `)` to close `(` started at 1:2");
+
+        let errors = parse("if a then b").unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "Expected `else` to close `if` started at 1:1"
+        );
+        assert_eq!(errors[0].span.offset.start, 11);
+
+        let errors = parse("(let a = 1)").unwrap_err();
+        assert_eq!(errors[0].msg, "Expected `in` to close `let` started at 1:2");
+    }
+
+    #[test]
+    fn t_missing_comma_error() {
+        let code = "let a = f(1)\n    b = g(2)\nin\n    a + b";
+        let errors = parse(code).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].msg, "Expected `,` or `in`");
+        assert_eq!(errors[0].span.offset.start, 12);
+        assert_eq!(
+            (errors[0].span.loc.start.row, errors[0].span.loc.start.col),
+            (0, 12)
+        );
+
+        let errors = parse("let a = 1, b = 2 c = 3 in a").unwrap_err();
+        assert_eq!(errors[0].msg, "Expected `,` or `in`");
+        assert_eq!(errors[0].span.offset.start, 16);
+    }
+
+    #[test]
+    fn t_parse_session() {
+        let mut session = ParseSession::new("a = 1\nb = a + 1\nb * 2");
+        let edit = |start, end, text: &str| Edit {
+            start,
+            end,
+            text: text.to_string(),
+        };
+
+        // Changing a token keeps the shape of the tree.
+        let changes = session.edit(&edit(4, 5, "10"));
+        assert_eq!(session.code(), "a = 10\nb = a + 1\nb * 2");
+        assert_eq!(changes.changed, vec![0]);
+        assert_eq!(changes.unchanged, vec![(1, 1), (2, 2)]);
+        match &session.unit().nodes[0] {
+            Ex::Binding(b) => assert!(matches!(&b.t.rhs, Ex::ConstInt(n) if *n.t == 10)),
+            other => panic!("Expected a binding, got: {:?}", other),
+        }
+
+        let changes = session.edit(&edit(7, 7, "c = 3\n"));
+        assert_eq!(changes.changed, vec![1]);
+        assert_eq!(changes.unchanged, vec![(0, 0), (1, 2), (2, 3)]);
+        let span = session.unit().nodes[3].src_span();
+        assert_eq!(span.offset.start, 23);
+        assert_eq!(span.loc.start.row, 3);
+
+        let changes = session.edit(&edit(7, 13, ""));
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.unchanged, vec![(0, 0), (2, 1), (3, 2)]);
+
+        session.edit(&edit(0, 0, "let "));
+        assert!(!session.errors().is_empty());
+    }
+
+    #[test]
+    fn t_is_incomplete() {
+        assert!(is_incomplete("if 1 < 2 then"));
+        assert!(is_incomplete("let a = 1,\n    b = 2"));
+        assert!(is_incomplete("f(1,"));
+        assert!(!is_incomplete("let a = 1 in a"));
+        assert!(!is_incomplete("1 ) + 2"));
     }
 }
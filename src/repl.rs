@@ -0,0 +1,162 @@
+use fang::diagnostic::{Diagnostic, WithSources};
+use fang::parser::{self, ParsingError, WithCode};
+use fang::tast::Ex;
+use fang::{Engine, Error, Item};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+Enter an expression to evaluate it or a binding to define a name.
+Inputs continue on the next line while they are incomplete, an empty line
+submits them as they are.
+
+  :type <expr>   Show the type of an expression
+  :ast <expr>    Show the syntax tree of an expression
+  :load <file>   Evaluate a file and keep its definitions
+  :help          Show this message
+  :quit          Exit";
+
+/// Interactive session. Every input is checked and evaluated in the context of
+/// the definitions made by the inputs before it.
+pub struct Repl {
+    engine: Engine,
+    /// Print errors as JSON objects instead of text.
+    json: bool,
+}
+
+impl Repl {
+    pub fn new(json: bool) -> Repl {
+        Repl {
+            engine: Engine::new(),
+            json,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        println!("fang repl, :help for help");
+        while let Some(input) = read_input(&mut lines)? {
+            let input = input.trim();
+            match command(input) {
+                _ if input.is_empty() => {}
+                Some((":quit", _)) | Some((":q", _)) => break,
+                Some((":help", _)) => println!("{}", HELP),
+                Some((":type", "")) => eprintln!("Usage: :type <expr>"),
+                Some((":ast", "")) => eprintln!("Usage: :ast <expr>"),
+                Some((":load", "")) => eprintln!("Usage: :load <file>"),
+                Some((":type", code)) => self.show_type(code),
+                Some((":ast", code)) => self.show_ast(code),
+                Some((":load", file)) => self.load(file),
+                Some((cmd, _)) => eprintln!("Unknown command: {}", cmd),
+                None => self.eval(input),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self, file: &str) {
+        match fs::read_to_string(file) {
+            Ok(code) => self.eval(&code),
+            Err(e) => eprintln!("Cannot read {}: {}", file, e),
+        }
+    }
+
+    /// Evaluates the code and prints the value of each expression in it. The
+    /// definitions are kept only if the whole input succeeds.
+    fn eval(&mut self, code: &str) {
+        match self.engine.run(code) {
+            Ok(items) => {
+                for item in items {
+                    match item {
+                        Item::Def(name, ty) => println!("{} : {}", name, ty),
+                        Item::Value(val) => println!("{}", val),
+                    }
+                }
+            }
+            // Runtime errors can point into the code of earlier inputs.
+            Err(Error::Runtime(e)) => self.print_errors(None, vec![e.diagnostic()]),
+            Err(e) => self.print_errors(Some(code), e.diagnostics()),
+        }
+    }
+
+    fn show_type(&self, code: &str) {
+        match self.engine.check(code) {
+            Ok(exs) => {
+                for ex in exs {
+                    match ex {
+                        Ex::Bind(b) => println!("{} : {}", b.name.t, b.ex.ty()),
+                        other => println!("{}", other.ty()),
+                    }
+                }
+            }
+            Err(e) => self.print_errors(Some(code), e.diagnostics()),
+        }
+    }
+
+    fn show_ast(&self, code: &str) {
+        match parser::parse(code) {
+            Ok(ast) => println!("{:#?}", ast),
+            Err(errors) => self.print_errors(
+                Some(code),
+                errors.iter().map(ParsingError::diagnostic).collect(),
+            ),
+        }
+    }
+
+    /// Prints the errors against `code`, or against the code of the runs so
+    /// far if it's not given.
+    fn print_errors(&self, code: Option<&str>, diagnostics: Vec<Diagnostic>) {
+        for d in diagnostics {
+            if self.json {
+                eprintln!("{}", d.to_json());
+            } else if let Some(code) = code {
+                eprintln!("{}", WithCode::new(code, &d));
+            } else {
+                eprintln!("{}", WithSources::new(self.engine.sources(), &d));
+            }
+        }
+    }
+}
+
+/// Reads lines until they form a complete input or an empty line is entered.
+/// Returns `None` at the end of the input stream.
+fn read_input(lines: &mut impl Iterator<Item = io::Result<String>>) -> io::Result<Option<String>> {
+    let mut input = String::new();
+
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "| " });
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None if input.is_empty() => return Ok(None),
+            None => return Ok(Some(input)),
+        };
+        if line.trim().is_empty() {
+            return Ok(Some(input));
+        }
+
+        input.push_str(&line);
+        input.push('\n');
+        let code = match command(&input) {
+            Some((":type", code)) | Some((":ast", code)) => code,
+            Some(_) => return Ok(Some(input)),
+            None => &input,
+        };
+        if !parser::is_incomplete(code) {
+            return Ok(Some(input));
+        }
+    }
+}
+
+/// The name and the argument of a command, like `:type` and the code after it.
+fn command(input: &str) -> Option<(&str, &str)> {
+    if !input.starts_with(':') {
+        return None;
+    }
+    let (name, arg) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    Some((name.trim(), arg.trim()))
+}
@@ -0,0 +1,281 @@
+use crate::builtin::{Builtin, Builtins, B};
+use crate::parser::{self, CompilationUnit, Ident, ParsingError, Span};
+use crate::tast::NameDef;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Result<A> = std::result::Result<A, Vec<ParsingError>>;
+
+/// What an identifier occurrence refers to.
+#[derive(Debug, Clone)]
+pub enum Res {
+    /// The definition the name refers to.
+    User(NameDef),
+    Builtin(Arc<Builtin>),
+}
+
+/// Resolution of every identifier in a compilation unit, keyed by the
+/// identifier's span. Each definition gets a new `NameDef`.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    refs: HashMap<Span<usize>, Res>,
+    defs: HashMap<Span<usize>, NameDef>,
+}
+
+impl Resolution {
+    pub fn get(&self, ident: &parser::N<Ident>) -> Option<Res> {
+        self.refs.get(&ident.offset_span).cloned()
+    }
+
+    /// The name defined by a binding or a parameter.
+    pub fn def(&self, ident: &parser::N<Ident>) -> Option<NameDef> {
+        self.defs.get(&ident.offset_span).cloned()
+    }
+}
+
+pub fn resolve_unit(unit: &CompilationUnit) -> Result<Resolution> {
+    resolve_unit_in(unit, &[], &B)
+}
+
+/// Resolves a unit that can also refer to `known` names defined outside of it,
+/// e.g. by earlier REPL inputs, and to the given builtins. Later known names
+/// shadow earlier ones, and the unit's own names may shadow all of them.
+pub fn resolve_unit_in(
+    unit: &CompilationUnit,
+    known: &[NameDef],
+    builtins: &Builtins,
+) -> Result<Resolution> {
+    let mut resolver = Resolver {
+        scopes: vec![],
+        builtins,
+        resolution: Resolution::default(),
+        errors: vec![],
+    };
+
+    let known = known
+        .iter()
+        .map(|name| (name.as_str().to_string(), name.clone()))
+        .collect();
+    resolver.scopes.push(known);
+    resolver.push();
+    for node in &unit.nodes {
+        match node {
+            parser::Ex::Binding(bind) => resolver.binding(bind),
+            other => resolver.ex(other),
+        }
+    }
+    resolver.pop();
+    resolver.pop();
+
+    if resolver.errors.is_empty() {
+        Ok(resolver.resolution)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+struct Resolver<'a> {
+    scopes: Vec<HashMap<String, NameDef>>,
+    builtins: &'a Builtins,
+    resolution: Resolution,
+    errors: Vec<ParsingError>,
+}
+
+impl Resolver<'_> {
+    fn push(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, ident: &parser::N<Ident>) {
+        let scope = self
+            .scopes
+            .last_mut()
+            .expect("No scope to declare a name in");
+        let name = NameDef::new(&ident.t.0);
+        if scope.insert(ident.t.0.clone(), name.clone()).is_some() {
+            let msg = format!("Duplicate name: {}", ident.t.0);
+            self.errors.push(ParsingError::at(msg, ident));
+        }
+        self.resolution.defs.insert(ident.offset_span, name);
+    }
+
+    fn reference(&mut self, ident: &parser::N<Ident>) {
+        let name = &ident.t.0;
+        let res = match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(def) => Some(Res::User(def.clone())),
+            None => self.builtins.get(name).cloned().map(Res::Builtin),
+        };
+
+        match res {
+            Some(res) => {
+                self.resolution.refs.insert(ident.offset_span, res);
+            }
+            None => {
+                let msg = format!("Unbound name: {}", name);
+                self.errors.push(ParsingError::at(msg, ident));
+            }
+        }
+    }
+
+    /// Declares the binding in the current scope. Functions can refer to
+    /// themselves, plain values can only see the names defined before them.
+    fn binding(&mut self, bind: &parser::N<parser::Bind>) {
+        if is_function(bind) {
+            self.declare(&bind.t.lhs);
+            self.binding_rhs(bind);
+        } else {
+            self.binding_rhs(bind);
+            self.declare(&bind.t.lhs);
+        }
+    }
+
+    fn binding_rhs(&mut self, bind: &parser::N<parser::Bind>) {
+        self.push();
+        bind.t.params.iter().for_each(|p| self.declare(p));
+        self.ex(&bind.t.rhs);
+        self.pop();
+    }
+
+    fn ex(&mut self, ex: &parser::Ex) {
+        match ex {
+            parser::Ex::Application(ap) => {
+                self.ex(&ap.t.receiver);
+                ap.t.args.iter().for_each(|arg| self.ex(arg));
+            }
+            parser::Ex::Binding(bind) => {
+                // A binding nested inside an expression doesn't scope over
+                // anything but itself.
+                self.push();
+                self.binding(bind);
+                self.pop();
+            }
+            parser::Ex::Condition(cond) => {
+                self.ex(&cond.t.pred);
+                self.ex(&cond.t.then);
+                self.ex(&cond.t.els);
+            }
+            parser::Ex::ConstBool(_) | parser::Ex::ConstInt(_) | parser::Ex::Error(_) => {}
+            parser::Ex::Identifier(ident) => self.reference(ident),
+            parser::Ex::Infix(infix) => {
+                self.ex(&infix.t.lhs);
+                self.ex(&infix.t.rhs);
+            }
+            parser::Ex::Lambda(lam) => {
+                self.push();
+                lam.t.params.iter().for_each(|p| self.declare(p));
+                self.ex(&lam.t.body);
+                self.pop();
+            }
+            parser::Ex::Let(l) => {
+                self.push();
+                l.t.bindings.iter().for_each(|b| self.binding(b));
+                self.ex(&l.t.body);
+                self.pop();
+            }
+            parser::Ex::Prefix(prefix) => self.ex(&prefix.t.body),
+        }
+    }
+}
+
+fn is_function(bind: &parser::N<parser::Bind>) -> bool {
+    !bind.t.params.is_empty() || matches!(bind.t.rhs, parser::Ex::Lambda(_))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    fn resolve_str(code: &str) -> Result<Resolution> {
+        resolve_unit(&parse(code).unwrap())
+    }
+
+    fn error_count(code: &str) -> usize {
+        resolve_str(code).err().map_or(0, |errors| errors.len())
+    }
+
+    #[test]
+    fn t_resolve_scopes() {
+        assert_eq!(error_count("f x = x + 1\nf(2)"), 0);
+        assert_eq!(error_count("fib n = if n < 2 then 1 else fib(n - 1)"), 0);
+        assert_eq!(error_count(r"let a = 1, b = \a -> a in b(a)"), 0);
+        assert_eq!(error_count("n = 1\nm = n + 1"), 0);
+    }
+
+    #[test]
+    fn t_resolve_unbound() {
+        assert_eq!(error_count("x + 1"), 1);
+        assert_eq!(error_count("n = n + 1"), 1);
+        assert_eq!(error_count("let a = b, b = 1 in a + c"), 2);
+        assert_eq!(error_count(r"(\x -> x)(x)"), 1);
+    }
+
+    #[test]
+    fn t_resolve_duplicates() {
+        assert_eq!(error_count("f x x = x"), 1);
+        assert_eq!(error_count("let a = 1, a = 2 in a"), 1);
+        assert_eq!(error_count("n = 1\nn = 2"), 1);
+    }
+
+    #[test]
+    fn t_resolve_known_names() {
+        let known = vec![NameDef::new("n")];
+        assert!(resolve_unit_in(&parse("n + 1").unwrap(), &known, &B).is_ok());
+        assert!(resolve_unit_in(&parse("n = n + 1\nn").unwrap(), &known, &B).is_ok());
+        assert!(resolve_unit_in(&parse("m + 1").unwrap(), &known, &B).is_err());
+    }
+
+    #[test]
+    fn t_resolve_builtins() {
+        let unit = parse("plus(1, 2)\nplus = 3\nplus").unwrap();
+        let resolution = resolve_unit(&unit).unwrap();
+
+        let idents: Vec<_> = unit
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                parser::Ex::Application(ap) => Some(&ap.t.receiver),
+                parser::Ex::Identifier(_) => Some(node),
+                _ => None,
+            })
+            .map(|ex| match ex {
+                parser::Ex::Identifier(ident) => resolution.get(ident),
+                _ => None,
+            })
+            .collect();
+
+        assert!(matches!(idents[0], Some(Res::Builtin(_))));
+        assert!(matches!(idents[1], Some(Res::User(_))));
+    }
+
+    #[test]
+    fn t_resolve_symbols() {
+        let unit = parse("n = 20\nf n = n\nn").unwrap();
+        let resolution = resolve_unit(&unit).unwrap();
+
+        let (top, f) = match (&unit.nodes[0], &unit.nodes[1]) {
+            (parser::Ex::Binding(top), parser::Ex::Binding(f)) => (top, f),
+            other => panic!("Expected two bindings, got: {:?}", other),
+        };
+        let top_n = resolution.def(&top.t.lhs).unwrap();
+        let param_n = resolution.def(&f.t.params[0]).unwrap();
+        assert_ne!(top_n, param_n);
+        assert_eq!(top_n.as_str(), param_n.as_str());
+
+        let uses: Vec<_> = [&f.t.rhs, &unit.nodes[2]]
+            .iter()
+            .map(|ex| match ex {
+                parser::Ex::Identifier(ident) => match resolution.get(ident) {
+                    Some(Res::User(def)) => def,
+                    other => panic!("Expected a user name, got: {:?}", other),
+                },
+                other => panic!("Expected an identifier, got: {:?}", other),
+            })
+            .collect();
+        assert_eq!(uses, vec![param_n, top_n]);
+    }
+}
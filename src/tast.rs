@@ -1,6 +1,9 @@
-use crate::builtin::{BuiltinName, B};
+use crate::builtin::Builtin;
+use crate::parser::{SourceId, SrcSpan};
 use crate::ty::Ty;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use indented::indented;
 use std::{fmt::Display, sync::Arc};
@@ -9,11 +12,43 @@ use std::{fmt::Display, sync::Arc};
 pub struct N<T> {
     pub t: Arc<T>,
     pub ty: Ty,
+    /// Where the node comes from, `None` for builtins and synthesized nodes.
+    pub span: Option<SrcSpan>,
 }
 
 impl<T> N<T> {
     pub fn new(t: T, ty: Ty) -> Self {
-        Self { t: Arc::new(t), ty }
+        Self {
+            t: Arc::new(t),
+            ty,
+            span: None,
+        }
+    }
+
+    pub fn spanned(t: T, ty: Ty, span: SrcSpan) -> Self {
+        Self {
+            t: Arc::new(t),
+            ty,
+            span: Some(span),
+        }
+    }
+
+    /// Creates a node at the same location as this one.
+    pub fn with<U>(&self, t: U, ty: Ty) -> N<U> {
+        N {
+            t: Arc::new(t),
+            ty,
+            span: self.span,
+        }
+    }
+
+    /// Creates a node at the same location as this one, but in `source`.
+    fn moved<U>(&self, t: U, source: SourceId) -> N<U> {
+        N {
+            t: Arc::new(t),
+            ty: self.ty.clone(),
+            span: self.span.map(|span| span.in_source(source)),
+        }
     }
 }
 
@@ -25,12 +60,89 @@ impl<T> Deref for N<T> {
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub struct NameDef(pub String);
+/// Identity of a user name, unique among all the names ever defined.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SymbolId(u64);
+
+impl SymbolId {
+    fn fresh() -> SymbolId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        SymbolId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A user name. Distinct definitions are distinct names even when they are
+/// spelled the same, so names are compared by their symbol only.
+#[derive(Debug, Clone)]
+pub struct NameDef {
+    id: SymbolId,
+    spelling: Arc<str>,
+}
+
+impl NameDef {
+    /// Defines a new name.
+    pub fn new(spelling: &str) -> NameDef {
+        NameDef {
+            id: SymbolId::fresh(),
+            spelling: spelling.into(),
+        }
+    }
+
+    pub fn id(&self) -> SymbolId {
+        self.id
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.spelling
+    }
+}
+
+impl PartialEq for NameDef {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for NameDef {}
+
+impl Hash for NameDef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl Display for NameDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spelling)
+    }
+}
 
 impl Display for N<NameDef> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Name {}: {}", self.t.0, self.ty)
+        write!(f, "Name {}: {}", self.t, self.ty)
+    }
+}
+
+/// Where the value of a variable is kept at runtime: in the frame `depth`
+/// levels out from the innermost one, at `index`. Frames are made by the top
+/// level, by lets and by calls of lambdas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addr {
+    pub depth: usize,
+    pub index: usize,
+}
+
+/// A reference to a user name.
+#[derive(Debug, Clone)]
+pub struct Var {
+    pub name: NameDef,
+    pub addr: Addr,
+}
+
+impl Display for N<Var> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Addr { depth, index } = self.t.addr;
+        write!(f, "Var {}@{}.{}: {}", self.t.name, depth, index, self.ty)
     }
 }
 
@@ -42,21 +154,27 @@ pub struct Prog {
 #[derive(Debug, Clone)]
 pub struct Lambda {
     pub bound: Vec<N<NameDef>>,
+    /// User names the body refers to that are bound outside of the lambda.
     pub free: Vec<N<NameDef>>,
     pub body: Ex,
 }
 
 impl Display for N<Lambda> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // TODO: handle free vars
         let bounds = self
             .bound
             .iter()
             .map(|b| format!("{}", b))
             .collect::<Vec<_>>()
             .join(",");
+        let frees = self
+            .free
+            .iter()
+            .map(|b| format!("{}", b))
+            .collect::<Vec<_>>()
+            .join(",");
 
-        writeln!(f, "λ ({}): {},", bounds, self.ty)?;
+        writeln!(f, "λ ({}) [{}]: {},", bounds, frees, self.ty)?;
         write!(f, "{}", indented(&self.t.body))
     }
 }
@@ -74,6 +192,23 @@ impl Display for N<Binding> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Let {
+    pub bindings: Vec<N<Binding>>,
+    pub body: Ex,
+}
+
+impl Display for N<Let> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "let")?;
+        for b in &self.t.bindings {
+            writeln!(f, "{}", indented(b))?;
+        }
+        writeln!(f, "in")?;
+        write!(f, "{}", indented(&self.t.body))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Application {
     pub ex: Ex,
@@ -112,13 +247,14 @@ impl Display for N<Condition> {
 #[derive(Debug, Clone)]
 pub enum Ex {
     Bind(N<Binding>),
+    Let(N<Let>),
     Lam(N<Lambda>),
     Ap(N<Application>),
     Cond(N<Condition>),
-    URef(N<NameDef>),
-    BRef(&'static N<BuiltinName>),
-    ConstInt(i64),
-    ConstBool(bool),
+    URef(N<Var>),
+    BRef(Arc<Builtin>),
+    ConstInt(N<i64>),
+    ConstBool(N<bool>),
 }
 
 impl Ex {
@@ -126,42 +262,184 @@ impl Ex {
     pub fn ty(&self) -> &Ty {
         match self {
             Ex::Bind(n) => &n.ty,
+            Ex::Let(n) => &n.ty,
             Ex::Lam(n) => &n.ty,
             Ex::Ap(n) => &n.ty,
             Ex::Cond(n) => &n.ty,
             Ex::URef(r) => &r.ty,
             Ex::BRef(r) => &r.ty,
-            Ex::ConstInt(_) => &Ty::Int,
-            Ex::ConstBool(_) => &Ty::Bool,
+            Ex::ConstInt(n) => &n.ty,
+            Ex::ConstBool(n) => &n.ty,
+        }
+    }
+
+    pub fn span(&self) -> Option<SrcSpan> {
+        match self {
+            Ex::Bind(n) => n.span,
+            Ex::Let(n) => n.span,
+            Ex::Lam(n) => n.span,
+            Ex::Ap(n) => n.span,
+            Ex::Cond(n) => n.span,
+            Ex::URef(r) => r.span,
+            Ex::BRef(_) => None,
+            Ex::ConstInt(n) => n.span,
+            Ex::ConstBool(n) => n.span,
+        }
+    }
+}
+
+impl Ex {
+    /// Rebuilds the tree with `f` applied to the type of every node, outer
+    /// nodes first.
+    pub fn map_ty<F: FnMut(&Ty) -> Ty>(&self, f: &mut F) -> Ex {
+        match self {
+            Ex::Bind(b) => b.map_ty(f).into(),
+            Ex::Let(l) => {
+                let ty = f(&l.ty);
+                let bindings = l.bindings.iter().map(|b| b.map_ty(f)).collect();
+                let body = l.body.map_ty(f);
+                l.with(Let { bindings, body }, ty).into()
+            }
+            Ex::Lam(l) => {
+                let ty = f(&l.ty);
+                let lam = Lambda {
+                    bound: l.bound.iter().map(|n| n.map_ty(f)).collect(),
+                    free: l.free.iter().map(|n| n.map_ty(f)).collect(),
+                    body: l.body.map_ty(f),
+                };
+                l.with(lam, ty).into()
+            }
+            Ex::Ap(a) => {
+                let ty = f(&a.ty);
+                let app = Application {
+                    ex: a.ex.map_ty(f),
+                    args: a.args.iter().map(|arg| arg.map_ty(f)).collect(),
+                };
+                a.with(app, ty).into()
+            }
+            Ex::Cond(c) => {
+                let ty = f(&c.ty);
+                let cond = Condition {
+                    pred: c.pred.map_ty(f),
+                    then: c.then.map_ty(f),
+                    els: c.els.map_ty(f),
+                };
+                c.with(cond, ty).into()
+            }
+            Ex::URef(r) => r.with(r.t.as_ref().clone(), f(&r.ty)).into(),
+            Ex::BRef(_) | Ex::ConstInt(_) | Ex::ConstBool(_) => self.clone(),
         }
     }
 }
 
+impl Ex {
+    /// Rebuilds the tree with every span moved to `source`.
+    pub fn in_source(&self, source: SourceId) -> Ex {
+        match self {
+            Ex::Bind(b) => b.in_source(source).into(),
+            Ex::Let(l) => {
+                let bindings = l.bindings.iter().map(|b| b.in_source(source)).collect();
+                let body = l.body.in_source(source);
+                l.moved(Let { bindings, body }, source).into()
+            }
+            Ex::Lam(l) => {
+                let lam = Lambda {
+                    bound: l.bound.iter().map(|n| n.in_source(source)).collect(),
+                    free: l.free.iter().map(|n| n.in_source(source)).collect(),
+                    body: l.body.in_source(source),
+                };
+                l.moved(lam, source).into()
+            }
+            Ex::Ap(a) => {
+                let app = Application {
+                    ex: a.ex.in_source(source),
+                    args: a.args.iter().map(|arg| arg.in_source(source)).collect(),
+                };
+                a.moved(app, source).into()
+            }
+            Ex::Cond(c) => {
+                let cond = Condition {
+                    pred: c.pred.in_source(source),
+                    then: c.then.in_source(source),
+                    els: c.els.in_source(source),
+                };
+                c.moved(cond, source).into()
+            }
+            Ex::URef(r) => r.moved(r.t.as_ref().clone(), source).into(),
+            Ex::ConstInt(n) => Ex::ConstInt(n.moved(*n.t, source)),
+            Ex::ConstBool(n) => Ex::ConstBool(n.moved(*n.t, source)),
+            Ex::BRef(_) => self.clone(),
+        }
+    }
+}
+
+impl N<Binding> {
+    pub fn map_ty<F: FnMut(&Ty) -> Ty>(&self, f: &mut F) -> N<Binding> {
+        let ty = f(&self.ty);
+        let binding = Binding {
+            name: self.name.map_ty(f),
+            ex: self.ex.map_ty(f),
+        };
+        self.with(binding, ty)
+    }
+
+    pub fn in_source(&self, source: SourceId) -> N<Binding> {
+        let binding = Binding {
+            name: self.name.in_source(source),
+            ex: self.ex.in_source(source),
+        };
+        self.moved(binding, source)
+    }
+}
+
+impl N<NameDef> {
+    pub fn map_ty<F: FnMut(&Ty) -> Ty>(&self, f: &mut F) -> N<NameDef> {
+        self.with(self.t.as_ref().clone(), f(&self.ty))
+    }
+
+    pub fn in_source(&self, source: SourceId) -> N<NameDef> {
+        self.moved(self.t.as_ref().clone(), source)
+    }
+}
+
 impl Display for Ex {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Ex::Bind(b) => write!(f, "{}", b)?,
+            Ex::Let(l) => write!(f, "{}", l)?,
             Ex::Lam(l) => write!(f, "{}", l)?,
             Ex::Ap(a) => write!(f, "{}", a)?,
             Ex::Cond(c) => write!(f, "{}", c)?,
             Ex::URef(r) => write!(f, "{}", r)?,
             Ex::BRef(r) => write!(f, "{}", r)?,
-            Ex::ConstInt(i) => write!(f, "{}", i)?,
-            Ex::ConstBool(b) => write!(f, "{}", b)?,
+            Ex::ConstInt(i) => write!(f, "{}", i.t)?,
+            Ex::ConstBool(b) => write!(f, "{}", b.t)?,
         }
 
         Ok(())
     }
 }
 
-impl From<N<NameDef>> for Ex {
-    fn from(v: N<NameDef>) -> Self {
+impl From<i64> for Ex {
+    fn from(v: i64) -> Self {
+        Ex::ConstInt(N::new(v, Ty::Int))
+    }
+}
+
+impl From<bool> for Ex {
+    fn from(v: bool) -> Self {
+        Ex::ConstBool(N::new(v, Ty::Bool))
+    }
+}
+
+impl From<N<Var>> for Ex {
+    fn from(v: N<Var>) -> Self {
         Ex::URef(v)
     }
 }
 
-impl From<&'static N<BuiltinName>> for Ex {
-    fn from(v: &'static N<BuiltinName>) -> Self {
+impl From<Arc<Builtin>> for Ex {
+    fn from(v: Arc<Builtin>) -> Self {
         Ex::BRef(v)
     }
 }
@@ -184,42 +462,62 @@ impl From<N<Lambda>> for Ex {
     }
 }
 
+impl From<N<Let>> for Ex {
+    fn from(v: N<Let>) -> Self {
+        Ex::Let(v)
+    }
+}
+
 impl From<N<Binding>> for Ex {
     fn from(v: N<Binding>) -> Self {
         Ex::Bind(v)
     }
 }
 
-#[allow(dead_code)]
+#[cfg(test)]
 pub mod example {
-    use super::Ex::*;
     use super::*;
+    use crate::builtin::B;
+    use once_cell::sync::Lazy;
+
+    // Top-level names are shared by their definitions and references.
+    static FIB: Lazy<NameDef> = Lazy::new(|| NameDef::new("fib"));
+    static INC: Lazy<NameDef> = Lazy::new(|| NameDef::new("inc"));
+
+    /// Top-level names are referred to as if they were the first definition.
+    fn var(name: &N<NameDef>, depth: usize, index: usize) -> Ex {
+        let var = Var {
+            name: name.t.as_ref().clone(),
+            addr: Addr { depth, index },
+        };
+        name.with(var, name.ty.clone()).into()
+    }
 
     pub fn fibonacci() -> Binding {
-        let less = &B.less;
-        let plus = &B.plus;
-        let minus = &B.minus;
+        let less = &B["less"];
+        let plus = &B["plus"];
+        let minus = &B["minus"];
 
-        let fib = N::new(NameDef("fib".to_string()), Ty::mk_func_1(Ty::Int, Ty::Int));
-        let n = N::new(NameDef("n".to_string()), Ty::Int);
+        let fib = N::new(FIB.clone(), Ty::mk_func_1(Ty::Int, Ty::Int));
+        let n = N::new(NameDef::new("n"), Ty::Int);
 
         let pred = Application {
-            ex: less.into(),
-            args: vec![n.clone().into(), ConstInt(2)],
+            ex: less.clone().into(),
+            args: vec![var(&n, 0, 1), 2.into()],
         };
 
-        let then = ConstInt(1);
+        let then: Ex = 1.into();
 
         let els = Application {
-            ex: plus.into(),
+            ex: plus.clone().into(),
             args: vec![
                 N::new(
                     Application {
-                        ex: fib.clone().into(),
+                        ex: var(&fib, 0, 0),
                         args: vec![N::new(
                             Application {
-                                ex: minus.into(),
-                                args: vec![n.clone().into(), ConstInt(1)],
+                                ex: minus.clone().into(),
+                                args: vec![var(&n, 0, 1), 1.into()],
                             },
                             Ty::Int,
                         )
@@ -230,11 +528,11 @@ pub mod example {
                 .into(),
                 N::new(
                     Application {
-                        ex: fib.clone().into(),
+                        ex: var(&fib, 0, 0),
                         args: vec![N::new(
                             Application {
-                                ex: minus.into(),
-                                args: vec![n.clone().into(), ConstInt(2)],
+                                ex: minus.clone().into(),
+                                args: vec![var(&n, 0, 1), 2.into()],
                             },
                             Ty::Int,
                         )
@@ -248,19 +546,19 @@ pub mod example {
 
         let body = Condition {
             pred: N::new(pred, Ty::Bool).into(),
-            then: then,
+            then,
             els: N::new(els, Ty::Int).into(),
         };
 
         let lam = Lambda {
-            bound: vec![n.clone().into()],
+            bound: vec![n.clone()],
             free: vec![],
             body: N::new(body, Ty::Int).into(),
         };
         let lam_ty = Ty::mk_func_1(Ty::Int, Ty::Int);
 
         Binding {
-            name: fib.clone().into(),
+            name: fib.clone(),
             ex: N::new(lam, lam_ty).into(),
         }
     }
@@ -272,19 +570,19 @@ pub mod example {
     }
 
     pub fn fibonacci_ap_n(n: i64) -> Ex {
-        let fib = N::new(NameDef("fib".to_string()), Ty::mk_func_1(Ty::Int, Ty::Int));
+        let fib = N::new(FIB.clone(), Ty::mk_func_1(Ty::Int, Ty::Int));
         let app = Application {
-            ex: fib.into(),
-            args: vec![ConstInt(n)],
+            ex: var(&fib, 0, 0),
+            args: vec![n.into()],
         };
         N::new(app, Ty::Int).into()
     }
 
     pub fn const_binding() -> Binding {
-        let name = N::new(NameDef("n".to_string()), Ty::Int);
+        let name = N::new(NameDef::new("n"), Ty::Int);
         Binding {
             name,
-            ex: ConstInt(42),
+            ex: 42.into(),
         }
     }
 
@@ -294,16 +592,16 @@ pub mod example {
     }
 
     pub fn inc_binding() -> Binding {
-        let plus = &B.plus;
-        let n = N::new(NameDef("n".to_string()), Ty::Int);
+        let plus = &B["plus"];
+        let n = N::new(NameDef::new("n"), Ty::Int);
 
         let lam = Lambda {
             bound: vec![n.clone()],
             free: vec![],
             body: N::new(
                 Application {
-                    ex: plus.into(),
-                    args: vec![n.clone().into(), ConstInt(1)],
+                    ex: plus.clone().into(),
+                    args: vec![var(&n, 0, 1), 1.into()],
                 },
                 Ty::Int,
             )
@@ -312,7 +610,7 @@ pub mod example {
 
         let ty = Ty::mk_func_1(Ty::Int, Ty::Int);
         Binding {
-            name: N::new(NameDef("inc".to_string()), ty.clone()),
+            name: N::new(INC.clone(), ty.clone()),
             ex: N::new(lam, ty).into(),
         }
     }
@@ -324,28 +622,60 @@ pub mod example {
 
     pub fn inc_ap_n(n: i64) -> Ex {
         let ty = Ty::mk_func_1(Ty::Int, Ty::Int);
-        let ex = N::new(NameDef("inc".to_string()), ty).into();
+        let ex = var(&N::new(INC.clone(), ty), 0, 0);
         let app = Application {
             ex,
-            args: vec![ConstInt(n)],
+            args: vec![n.into()],
         };
         N::new(app, Ty::Int).into()
     }
 
     pub fn incinc_ap_n(n: i64) -> Ex {
         let ty = Ty::mk_func_1(Ty::Int, Ty::Int);
-        let inc_ref = N::new(NameDef("inc".to_string()), ty);
+        let inc_ref = var(&N::new(INC.clone(), ty), 0, 0);
 
         let app = Application {
-            ex: inc_ref.clone().into(),
-            args: vec![ConstInt(n)],
+            ex: inc_ref.clone(),
+            args: vec![n.into()],
         };
         let app = N::new(app, Ty::Int);
 
         let app2 = Application {
-            ex: inc_ref.clone().into(),
+            ex: inc_ref.clone(),
             args: vec![app.into()],
         };
         N::new(app2, Ty::Int).into()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::example::*;
+    use crate::interp::Interp;
+    use crate::value::Value;
+    use pretty_assertions::assert_eq;
+
+    fn eval_int(interp: &mut Interp, ex: &super::Ex) -> i64 {
+        match interp.eval_top(ex).unwrap() {
+            Some(Value::Int(n)) => n,
+            other => panic!("Expected an int, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn t_examples() {
+        fibonacci();
+        const_binding();
+        assert!(matches!(const_binding_ex(), super::Ex::Bind(_)));
+
+        let mut interp = Interp::new();
+        interp.eval_top(&fibonacci_ex()).unwrap();
+        assert_eq!(eval_int(&mut interp, &fibonacci_ap_n(10)), 89);
+
+        let mut interp = Interp::new();
+        inc_binding();
+        interp.eval_top(&inc_ex()).unwrap();
+        assert_eq!(eval_int(&mut interp, &inc_ap_n(1)), 2);
+        assert_eq!(eval_int(&mut interp, &incinc_ap_n(1)), 3);
+    }
+}
@@ -1,20 +1,28 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+pub type TyVar = u32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Ty {
+    /// Type of a freshly lowered node that hasn't been inferred yet.
+    Unknown,
+    Var(TyVar),
     Int,
     Bool,
-    F { par: Vec<Ty>, ret: Box<Ty> },
+    F {
+        par: Vec<Ty>,
+        ret: Box<Ty>,
+    },
 }
 
 impl Ty {
-    #[allow(dead_code)]
-    pub fn mk_func_n<'a, 'b>(par: Vec<Ty>, ret: Ty) -> Ty {
+    pub fn mk_func_n(par: Vec<Ty>, ret: Ty) -> Ty {
         let ret = Box::new(ret);
         Ty::F { par, ret }
     }
 
-    pub fn mk_func_1<'a, 'b>(par: Ty, ret: Ty) -> Ty {
+    pub fn mk_func_1(par: Ty, ret: Ty) -> Ty {
         Ty::F {
             par: vec![par],
             ret: Box::new(ret),
@@ -27,11 +35,43 @@ impl Ty {
             ret: Box::new(ret),
         }
     }
+
+    /// Free type variables in the order of their first occurrence.
+    pub fn ftv(&self) -> Vec<TyVar> {
+        let mut vars = vec![];
+        self.collect_ftv(&mut vars);
+        vars
+    }
+
+    fn collect_ftv(&self, vars: &mut Vec<TyVar>) {
+        match self {
+            Ty::Var(v) => {
+                if !vars.contains(v) {
+                    vars.push(*v)
+                }
+            }
+            Ty::F { par, ret } => {
+                par.iter().for_each(|p| p.collect_ftv(vars));
+                ret.collect_ftv(vars);
+            }
+            Ty::Unknown | Ty::Int | Ty::Bool => {}
+        }
+    }
+}
+
+fn var_name(var: TyVar) -> String {
+    let letter = (b'a' + (var % 26) as u8) as char;
+    match var / 26 {
+        0 => letter.to_string(),
+        n => format!("{}{}", letter, n),
+    }
 }
 
 impl Display for Ty {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Ty::Unknown => write!(f, "?"),
+            Ty::Var(v) => write!(f, "{}", var_name(*v)),
             Ty::Int => write!(f, "Int"),
             Ty::Bool => write!(f, "Bool"),
             Ty::F { par, ret } => {
@@ -45,3 +85,124 @@ impl Display for Ty {
         }
     }
 }
+
+/// A type with some of its variables universally quantified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheme {
+    pub vars: Vec<TyVar>,
+    pub ty: Ty,
+}
+
+impl Scheme {
+    pub fn mono(ty: Ty) -> Scheme {
+        Scheme { vars: vec![], ty }
+    }
+
+    /// Quantifies over every free variable of the type.
+    pub fn poly(ty: Ty) -> Scheme {
+        Scheme { vars: ty.ftv(), ty }
+    }
+
+    pub fn ftv(&self) -> Vec<TyVar> {
+        let mut vars = self.ty.ftv();
+        vars.retain(|v| !self.vars.contains(v));
+        vars
+    }
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.vars.is_empty() {
+            return write!(f, "{}", self.ty);
+        }
+
+        // Quantified variables go first so they are named `a, b, c, ...`,
+        // free ones are numbered after them.
+        let free = self.ftv();
+        let renaming = self
+            .vars
+            .iter()
+            .chain(free.iter())
+            .zip(0..)
+            .map(|(&v, n)| (v, Ty::Var(n)))
+            .collect();
+        let ty = Subst { mapping: renaming }.apply(&self.ty);
+
+        let vars = (0..self.vars.len() as TyVar)
+            .map(var_name)
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "forall {}. {}", vars, ty)
+    }
+}
+
+/// Mapping from type variables to types.
+#[derive(Debug, Clone, Default)]
+pub struct Subst {
+    mapping: HashMap<TyVar, Ty>,
+}
+
+impl Subst {
+    pub fn new() -> Subst {
+        Subst::default()
+    }
+
+    pub fn insert(&mut self, var: TyVar, ty: Ty) {
+        self.mapping.insert(var, ty);
+    }
+
+    pub fn get(&self, var: TyVar) -> Option<&Ty> {
+        self.mapping.get(&var)
+    }
+
+    /// Replaces every mapped variable in a single pass, i.e. types it maps
+    /// to are not substituted again.
+    pub fn apply(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Var(v) => self.mapping.get(v).cloned().unwrap_or_else(|| ty.clone()),
+            Ty::F { par, ret } => {
+                Ty::mk_func_n(par.iter().map(|p| self.apply(p)).collect(), self.apply(ret))
+            }
+            Ty::Unknown | Ty::Int | Ty::Bool => ty.clone(),
+        }
+    }
+}
+
+impl std::iter::FromIterator<(TyVar, Ty)> for Subst {
+    fn from_iter<I: IntoIterator<Item = (TyVar, Ty)>>(iter: I) -> Self {
+        Subst {
+            mapping: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn t_display_vars() {
+        let ty = Ty::mk_func_2(Ty::Var(7), Ty::Var(3), Ty::Var(7));
+        assert_eq!(format!("{}", ty), "(h, d) -> h");
+        assert_eq!(format!("{}", Ty::Var(27)), "b1");
+
+        let scheme = Scheme {
+            vars: vec![3],
+            ty: Ty::mk_func_1(Ty::Var(3), Ty::Var(5)),
+        };
+        assert_eq!(format!("{}", scheme), "forall a. (a) -> b");
+        assert_eq!(format!("{}", Scheme::poly(ty)), "forall a b. (a, b) -> a");
+    }
+
+    #[test]
+    fn t_subst() {
+        let subst: Subst = vec![(0, Ty::Var(1)), (1, Ty::Var(0))].into_iter().collect();
+        let ty = Ty::mk_func_2(Ty::Var(0), Ty::Var(1), Ty::Var(2));
+        assert_eq!(
+            subst.apply(&ty),
+            Ty::mk_func_2(Ty::Var(1), Ty::Var(0), Ty::Var(2))
+        );
+        assert_eq!(subst.apply(&ty).ftv(), vec![1, 0, 2]);
+    }
+}
@@ -0,0 +1,623 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::{SrcSpan, WithCode};
+use crate::tast::{Application, Binding, Condition, Ex, Lambda, Let, NameDef, N};
+use crate::ty::{Scheme, Subst, Ty, TyVar};
+use rpds::HashTrieMap;
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+#[derive(Debug)]
+pub struct TypeError {
+    msg: String,
+    span: Option<SrcSpan>,
+}
+
+impl TypeError {
+    fn new(msg: String, span: Option<SrcSpan>) -> TypeError {
+        TypeError { msg, span }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::error(&self.msg).with_code("type").at(self.span)
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Type error: {}", self.msg)
+    }
+}
+
+impl<'a, 'b> Display for WithCode<'a, 'b, TypeError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", WithCode::new(self.code, &self.t.diagnostic()))
+    }
+}
+
+enum UnifyError {
+    Mismatch,
+    Infinite(TyVar, Ty),
+    /// A variable of equality was bound to a function type.
+    NoEquality(Ty),
+}
+
+type Result<A> = std::result::Result<A, TypeError>;
+
+#[derive(Debug, Clone, Default)]
+struct TyEnv {
+    mapping: HashTrieMap<NameDef, Scheme>,
+}
+
+impl TyEnv {
+    fn new() -> TyEnv {
+        TyEnv {
+            mapping: HashTrieMap::new(),
+        }
+    }
+
+    fn bind(&self, name: &NameDef, scheme: Scheme) -> TyEnv {
+        let mapping = self.mapping.insert(name.clone(), scheme);
+        Self { mapping }
+    }
+
+    fn find(&self, name: &NameDef) -> Option<&Scheme> {
+        self.mapping.get(name)
+    }
+}
+
+pub fn infer_unit(exs: &[Ex]) -> std::result::Result<Vec<Ex>, Vec<TypeError>> {
+    let mut typeck = Typeck::new();
+    let mut typed = vec![];
+    let mut errors = vec![];
+
+    for ex in exs {
+        match typeck.check_top(ex) {
+            Ok(ex) => typed.push(ex),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(typed)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Algorithm J: types are inferred in a single pass over the tree, solving
+/// equality constraints eagerly into a global substitution.
+#[derive(Clone, Default)]
+pub struct Typeck {
+    env: TyEnv,
+    subst: Subst,
+    next_var: TyVar,
+    /// Variables that only stand for types that can be compared with `==`,
+    /// which are the types other than functions.
+    eq_vars: HashSet<TyVar>,
+}
+
+impl Typeck {
+    pub fn new() -> Typeck {
+        Typeck {
+            env: TyEnv::new(),
+            subst: Subst::new(),
+            next_var: 0,
+            eq_vars: HashSet::new(),
+        }
+    }
+
+    /// Infers the types of a top-level expression. Top-level bindings are
+    /// visible to every expression checked after them.
+    pub fn check_top(&mut self, ex: &Ex) -> Result<Ex> {
+        let env = self.env.clone();
+
+        match ex {
+            Ex::Bind(b) => match self.infer_binding(&env, b) {
+                Ok((b, env)) => {
+                    self.env = env;
+                    Ok(self.zonk(&b.into()))
+                }
+                Err(e) => {
+                    // Let the rest of the unit see the name as `forall a. a`
+                    // to avoid a cascade of errors.
+                    let var = self.fresh_var();
+                    let scheme = Scheme {
+                        vars: vec![var],
+                        ty: Ty::Var(var),
+                    };
+                    self.env = env.bind(&b.name.t, scheme);
+                    Err(e)
+                }
+            },
+            other => {
+                let ex = self.infer(&env, other)?;
+                Ok(self.zonk(&ex))
+            }
+        }
+    }
+
+    fn fresh_var(&mut self) -> TyVar {
+        let var = self.next_var;
+        self.next_var += 1;
+        var
+    }
+
+    fn fresh(&mut self) -> Ty {
+        Ty::Var(self.fresh_var())
+    }
+
+    fn infer(&mut self, env: &TyEnv, ex: &Ex) -> Result<Ex> {
+        match ex {
+            Ex::Bind(b) => {
+                let (b, _) = self.infer_binding(env, b)?;
+                Ok(b.into())
+            }
+            Ex::Let(l) => {
+                let mut env = env.clone();
+                let mut bindings = vec![];
+                for b in &l.bindings {
+                    let (b, new_env) = self.infer_binding(&env, b)?;
+                    bindings.push(b);
+                    env = new_env;
+                }
+                let body = self.infer(&env, &l.body)?;
+                let ty = self.ty_of(&body);
+                Ok(l.with(Let { bindings, body }, ty).into())
+            }
+            Ex::Lam(l) => {
+                let bound: Vec<_> = l
+                    .bound
+                    .iter()
+                    .map(|n| n.with(n.t.as_ref().clone(), self.fresh()))
+                    .collect();
+                let body_env = bound.iter().fold(env.clone(), |env, n| {
+                    env.bind(&n.t, Scheme::mono(n.ty.clone()))
+                });
+                let body = self.infer(&body_env, &l.body)?;
+
+                let par = bound.iter().map(|n| n.ty.clone()).collect();
+                let ty = Ty::mk_func_n(par, self.ty_of(&body));
+                let free = l
+                    .free
+                    .iter()
+                    .map(|n| {
+                        let ty = env.find(&n.t).map_or(Ty::Unknown, |s| s.ty.clone());
+                        n.with(n.t.as_ref().clone(), ty)
+                    })
+                    .collect();
+                let lam = Lambda { bound, free, body };
+                Ok(l.with(lam, ty).into())
+            }
+            Ex::Ap(a) => {
+                let fun = self.infer(env, &a.ex)?;
+                let args = a
+                    .args
+                    .iter()
+                    .map(|arg| self.infer(env, arg))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let fun_ty = self.ty_of(&fun);
+                let arg_tys: Vec<_> = args
+                    .iter()
+                    .map(|arg| (self.ty_of(arg), arg.span().or(a.span)))
+                    .collect();
+                let ret = self.infer_ap(&fun_ty, &arg_tys, fun.span().or(a.span), a.span)?;
+
+                Ok(a.with(Application { ex: fun, args }, ret).into())
+            }
+            Ex::Cond(c) => {
+                let pred = self.infer(env, &c.pred)?;
+                let then = self.infer(env, &c.then)?;
+                let els = self.infer(env, &c.els)?;
+
+                let (pred_ty, ty, els_ty) =
+                    (self.ty_of(&pred), self.ty_of(&then), self.ty_of(&els));
+                self.expect(&Ty::Bool, &pred_ty, pred.span().or(c.span))?;
+                self.expect(&ty, &els_ty, els.span().or(c.span))?;
+
+                Ok(c.with(Condition { pred, then, els }, ty).into())
+            }
+            Ex::URef(r) => {
+                let scheme = env
+                    .find(&r.t.name)
+                    .ok_or_else(|| TypeError::new(format!("Unbound name: {}", r.t.name), r.span))?;
+                let ty = self.instantiate(scheme);
+                Ok(r.with(r.t.as_ref().clone(), ty).into())
+            }
+            Ex::BRef(_) | Ex::ConstInt(_) | Ex::ConstBool(_) => Ok(ex.clone()),
+        }
+    }
+
+    /// Type of applying a function of type `fun_ty` to arguments of the given
+    /// types. Fewer arguments than the function takes give a function of the
+    /// rest, extra ones are passed on to its result.
+    fn infer_ap(
+        &mut self,
+        fun_ty: &Ty,
+        args: &[(Ty, Option<SrcSpan>)],
+        fun_span: Option<SrcSpan>,
+        span: Option<SrcSpan>,
+    ) -> Result<Ty> {
+        match self.shallow(fun_ty) {
+            Ty::F { par, ret } => {
+                // Named before the arguments are unified with the parameters.
+                let shown = rename_vars(&[self.apply(fun_ty)]).remove(0);
+                for (par, (arg_ty, arg_span)) in par.iter().zip(args) {
+                    self.expect(par, arg_ty, *arg_span)?;
+                }
+                if args.len() <= par.len() {
+                    let rest = par[args.len()..].to_vec();
+                    return Ok(if rest.is_empty() {
+                        *ret
+                    } else {
+                        Ty::mk_func_n(rest, *ret)
+                    });
+                }
+                match self.shallow(&ret) {
+                    Ty::F { .. } | Ty::Var(_) => {
+                        self.infer_ap(&ret, &args[par.len()..], span, span)
+                    }
+                    _ => {
+                        let msg = format!(
+                            "expected {} for a function of type {}, found {}",
+                            plural(par.len(), "argument"),
+                            shown,
+                            args.len()
+                        );
+                        Err(TypeError::new(msg, span))
+                    }
+                }
+            }
+            Ty::Var(_) => {
+                let ret = self.fresh();
+                let par = args.iter().map(|(ty, _)| ty.clone()).collect();
+                let expected = Ty::mk_func_n(par, ret.clone());
+                self.expect(&expected, fun_ty, fun_span)?;
+                Ok(ret)
+            }
+            other => {
+                let msg = format!("expected a function, found {}", self.apply(&other));
+                Err(TypeError::new(msg, fun_span))
+            }
+        }
+    }
+
+    /// Type of a node as seen by its parent. Builtins are the only nodes that
+    /// can have a polymorphic type in the tree, so every use gets a fresh
+    /// instance.
+    fn ty_of(&mut self, ex: &Ex) -> Ty {
+        match ex {
+            Ex::BRef(b) => {
+                let ty = self.instantiate(&Scheme::poly(b.ty.clone()));
+                if b.name == "eq" {
+                    self.eq_vars.extend(ty.ftv());
+                }
+                ty
+            }
+            other => other.ty().clone(),
+        }
+    }
+
+    /// Infers the type of a binding and returns the environment extended with
+    /// its generalized type. A binding to a lambda can refer to itself.
+    fn infer_binding(&mut self, env: &TyEnv, b: &N<Binding>) -> Result<(N<Binding>, TyEnv)> {
+        let self_ty = self.fresh();
+        let rhs_env = if let Ex::Lam(_) = b.ex {
+            env.bind(&b.name.t, Scheme::mono(self_ty.clone()))
+        } else {
+            env.clone()
+        };
+
+        let ex = self.infer(&rhs_env, &b.ex)?;
+        let ex_ty = self.ty_of(&ex);
+        self.expect(&self_ty, &ex_ty, ex.span().or(b.span))?;
+
+        let scheme = self.generalize(env, &self_ty);
+        let name = b.name.with(b.name.t.as_ref().clone(), self_ty.clone());
+        let binding = b.with(Binding { name, ex }, self_ty);
+
+        Ok((binding, env.bind(&b.name.t, scheme)))
+    }
+
+    /// Unifies the type a node is expected to have with the one it actually
+    /// has, reporting a mismatch at the node's location.
+    fn expect(&mut self, expected: &Ty, actual: &Ty, span: Option<SrcSpan>) -> Result<()> {
+        self.unify(expected, actual).map_err(|e| {
+            let msg = match e {
+                UnifyError::Mismatch => {
+                    let tys = rename_vars(&[self.apply(expected), self.apply(actual)]);
+                    format!("expected {}, found {}", tys[0], tys[1])
+                }
+                UnifyError::Infinite(var, ty) => {
+                    let tys = rename_vars(&[Ty::Var(var), ty]);
+                    format!("infinite type: {} occurs in {}", tys[0], tys[1])
+                }
+                UnifyError::NoEquality(ty) => format!(
+                    "expected a type that can be compared with ==, found {}",
+                    rename_vars(&[ty])[0]
+                ),
+            };
+            TypeError::new(msg, span)
+        })
+    }
+
+    fn unify(&mut self, a: &Ty, b: &Ty) -> std::result::Result<(), UnifyError> {
+        let a = self.shallow(a);
+        let b = self.shallow(b);
+
+        match (&a, &b) {
+            (Ty::Int, Ty::Int) | (Ty::Bool, Ty::Bool) => Ok(()),
+            (Ty::Var(x), Ty::Var(y)) if x == y => Ok(()),
+            (Ty::Var(v), other) | (other, Ty::Var(v)) => self.bind_var(*v, other),
+            (Ty::F { par: p1, ret: r1 }, Ty::F { par: p2, ret: r2 }) if p1.len() == p2.len() => {
+                for (x, y) in p1.iter().zip(p2) {
+                    self.unify(x, y)?;
+                }
+                self.unify(r1, r2)
+            }
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    fn bind_var(&mut self, var: TyVar, ty: &Ty) -> std::result::Result<(), UnifyError> {
+        let ty = self.apply(ty);
+
+        if ty.ftv().contains(&var) {
+            Err(UnifyError::Infinite(var, ty))
+        } else if self.eq_vars.contains(&var) && !self.admit_eq(&ty) {
+            Err(UnifyError::NoEquality(ty))
+        } else {
+            self.subst.insert(var, ty);
+            Ok(())
+        }
+    }
+
+    /// Whether a variable of equality can be bound to the type. The variable
+    /// it's bound to, if any, becomes one too.
+    fn admit_eq(&mut self, ty: &Ty) -> bool {
+        match ty {
+            Ty::F { .. } => false,
+            Ty::Var(v) => {
+                self.eq_vars.insert(*v);
+                true
+            }
+            Ty::Unknown | Ty::Int | Ty::Bool => true,
+        }
+    }
+
+    /// Resolves a type variable to whatever it's bound to, one level deep.
+    fn shallow(&self, ty: &Ty) -> Ty {
+        let mut ty = ty;
+        while let Ty::Var(v) = ty {
+            match self.subst.get(*v) {
+                Some(bound) => ty = bound,
+                None => break,
+            }
+        }
+        ty.clone()
+    }
+
+    /// Fully resolves a type against the current substitution.
+    fn apply(&self, ty: &Ty) -> Ty {
+        match self.shallow(ty) {
+            Ty::F { par, ret } => Ty::mk_func_n(
+                par.iter().map(|p| self.apply(p)).collect(),
+                self.apply(&ret),
+            ),
+            other => other,
+        }
+    }
+
+    fn generalize(&self, env: &TyEnv, ty: &Ty) -> Scheme {
+        let ty = self.apply(ty);
+
+        let env_vars: Vec<_> = env
+            .mapping
+            .values()
+            .flat_map(|scheme| {
+                let ty = self.apply(&scheme.ty);
+                let vars = scheme.vars.clone();
+                Scheme { vars, ty }.ftv()
+            })
+            .collect();
+
+        let mut vars = ty.ftv();
+        vars.retain(|v| !env_vars.contains(v));
+
+        Scheme { vars, ty }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Ty {
+        let mut renaming = Subst::new();
+        for &v in &scheme.vars {
+            let fresh = self.fresh_var();
+            if self.eq_vars.contains(&v) {
+                self.eq_vars.insert(fresh);
+            }
+            renaming.insert(v, Ty::Var(fresh));
+        }
+        renaming.apply(&scheme.ty)
+    }
+
+    /// Replaces every solved type variable in the tree with its solution and
+    /// renumbers the unsolved ones from zero, so that each top-level tree has
+    /// its type variables named `a, b, c, ...` in order of appearance.
+    fn zonk(&self, ex: &Ex) -> Ex {
+        let mut renaming = Subst::new();
+        let mut next_var = 0;
+
+        ex.map_ty(&mut |ty| {
+            let ty = self.apply(ty);
+            for var in ty.ftv() {
+                if renaming.get(var).is_none() {
+                    renaming.insert(var, Ty::Var(next_var));
+                    next_var += 1;
+                }
+            }
+            renaming.apply(&ty)
+        })
+    }
+}
+
+/// Renames type variables shared by a group of types to `a, b, c, ...`, so
+/// that types reported together in a message are named consistently.
+fn rename_vars(tys: &[Ty]) -> Vec<Ty> {
+    let mut vars: Vec<TyVar> = vec![];
+    for ty in tys {
+        vars.extend(
+            ty.ftv()
+                .into_iter()
+                .filter(|v| !vars.contains(v))
+                .collect::<Vec<_>>(),
+        );
+    }
+    let renaming: Subst = vars
+        .into_iter()
+        .zip(0..)
+        .map(|(v, n)| (v, Ty::Var(n)))
+        .collect();
+    tys.iter().map(|ty| renaming.apply(ty)).collect()
+}
+
+fn plural(n: usize, word: &str) -> String {
+    if n == 1 {
+        format!("{} {}", n, word)
+    } else {
+        format!("{} {}s", n, word)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lower::lower_unit;
+    use crate::parser::parse;
+    use crate::resolve::resolve_unit;
+    use pretty_assertions::assert_eq;
+
+    fn infer_str(code: &str) -> std::result::Result<Vec<Ex>, Vec<TypeError>> {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        infer_unit(&lower_unit(&unit, &res).unwrap())
+    }
+
+    fn last_ty(code: &str) -> Ty {
+        infer_str(code).unwrap().last().unwrap().ty().clone()
+    }
+
+    #[test]
+    fn t_infer_basic() {
+        assert_eq!(last_ty("1 + 2"), Ty::Int);
+        assert_eq!(last_ty("if 1 < 2 then True else False"), Ty::Bool);
+        assert_eq!(
+            last_ty("fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)"),
+            Ty::mk_func_1(Ty::Int, Ty::Int)
+        );
+        assert_eq!(
+            last_ty("fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)\nfib(10)"),
+            Ty::Int
+        );
+    }
+
+    #[test]
+    fn t_infer_polymorphism() {
+        match last_ty(r"\x -> x") {
+            Ty::F { par, ret } => assert_eq!(par, vec![*ret]),
+            other => panic!("Expected a function type, got: {}", other),
+        }
+
+        assert_eq!(
+            last_ty("let id x = x in if id(True) then id(1) else 2"),
+            Ty::Int
+        );
+        assert_eq!(last_ty("id x = x\nid(True)\nid(1)"), Ty::Int);
+
+        assert_eq!(
+            last_ty("f n = if n < 0 then error() else True"),
+            Ty::mk_func_1(Ty::Int, Ty::Bool)
+        );
+        assert_eq!(last_ty("if error() then error() else 1"), Ty::Int);
+        assert_eq!(
+            format!("{}", last_ty(r"\f x -> f(f(x))")),
+            "((a) -> a, a) -> a"
+        );
+    }
+
+    #[test]
+    fn t_infer_errors() {
+        assert!(infer_str("1 + True").is_err());
+        assert!(infer_str("if 1 then 2 else 3").is_err());
+        assert!(infer_str("if True then 2 else False").is_err());
+        assert!(infer_str("plus(1)(True)").is_err());
+        assert!(infer_str(r"\f -> if f(True) then f(1) else 0").is_err());
+        assert!(infer_str("f x = f").is_err());
+    }
+
+    fn error_msgs(code: &str) -> Vec<(String, usize)> {
+        infer_str(code)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.msg, e.span.unwrap().offset.start))
+            .collect()
+    }
+
+    #[test]
+    fn t_infer_partial_application() {
+        assert_eq!(last_ty("plus(1)"), Ty::mk_func_1(Ty::Int, Ty::Int));
+        assert_eq!(last_ty("plus(1)(2)"), Ty::Int);
+        assert_eq!(last_ty(r"let k x = \y -> x in k(1, True)"), Ty::Int);
+        assert_eq!(
+            last_ty(r"(\x y z -> if x then y else z)(True, 1)"),
+            Ty::mk_func_1(Ty::Int, Ty::Int)
+        );
+    }
+
+    #[test]
+    fn t_infer_error_messages() {
+        assert_eq!(
+            error_msgs("1 + True"),
+            vec![("expected Int, found Bool".to_string(), 4)]
+        );
+        assert_eq!(
+            error_msgs("if 1 then 2 else 3"),
+            vec![("expected Bool, found Int".to_string(), 3)]
+        );
+        assert_eq!(
+            error_msgs("id x = x\nid(1, 2)"),
+            vec![(
+                "expected 1 argument for a function of type (a) -> a, found 2".to_string(),
+                9
+            )]
+        );
+        assert_eq!(
+            error_msgs("3(4)"),
+            vec![("expected a function, found Int".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn t_infer_equality() {
+        assert_eq!(last_ty("1 == 2"), Ty::Bool);
+        assert_eq!(last_ty("same x y = x == y\nsame(True, False)"), Ty::Bool);
+
+        let msg = "expected a type that can be compared with ==, found (a) -> a".to_string();
+        assert_eq!(error_msgs("id x = x\nid == id"), vec![(msg.clone(), 9)]);
+        assert_eq!(
+            error_msgs("same x y = x == y\nid x = x\nsame(id, id)"),
+            vec![(msg, 32)]
+        );
+        assert_eq!(
+            error_msgs(r"\f -> if f == f then f(1) else 0"),
+            vec![(
+                "expected a type that can be compared with ==, found (Int) -> a".to_string(),
+                21
+            )]
+        );
+    }
+
+    #[test]
+    fn t_infer_errors_dont_cascade() {
+        let errors = infer_str("n = 1 + True\nm = n + 1\nn").unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+}
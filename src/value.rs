@@ -0,0 +1,90 @@
+use crate::builtin::Builtin;
+use crate::interp::Env;
+use crate::tast::{Lambda, NameDef, N};
+use crate::vm;
+use std::fmt::{self, Display};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Result of evaluating an expression.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Closure(Rc<Closure>),
+    /// A closure made by the VM.
+    Compiled(Rc<vm::Closure>),
+    Builtin(Arc<Builtin>),
+    Partial(Rc<Partial>),
+}
+
+/// A lambda together with the values of its free names.
+#[derive(Debug)]
+pub struct Closure {
+    pub lam: N<Lambda>,
+    /// The name a function is bound to, so that it can call itself without
+    /// capturing itself in `env`.
+    pub name: Option<NameDef>,
+    pub env: Env,
+}
+
+/// A function applied to fewer arguments than it takes.
+#[derive(Debug)]
+pub struct Partial {
+    pub func: Value,
+    pub args: Vec<Value>,
+}
+
+impl Value {
+    /// Number of arguments still needed to call the function, `None` for
+    /// non-functions.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Value::Closure(c) => Some(c.lam.bound.len()),
+            Value::Compiled(c) => Some(c.proto.arity),
+            Value::Builtin(b) => Some(b.arity),
+            Value::Partial(p) => p.func.arity().map(|n| n - p.args.len()),
+            Value::Int(_) | Value::Bool(_) => None,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(true) => write!(f, "True"),
+            Value::Bool(false) => write!(f, "False"),
+            Value::Closure(c) => match &c.name {
+                Some(name) => write!(f, "<function {}>", name),
+                None => write!(f, "<lambda>"),
+            },
+            Value::Compiled(c) => match &c.proto.name {
+                Some(name) => write!(f, "<function {}>", name),
+                None => write!(f, "<lambda>"),
+            },
+            Value::Builtin(b) => write!(f, "<builtin {}>", b.name),
+            Value::Partial(p) => {
+                let args = p
+                    .args
+                    .iter()
+                    .map(|a| format!("{}", a))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "{}({}, ...)", p.func, args)
+            }
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
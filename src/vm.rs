@@ -0,0 +1,429 @@
+use crate::bytecode::{Capture, Compiler, Op, Proto};
+use crate::interp::{self, RuntimeError};
+use crate::limits::{Budget, Limits};
+use crate::parser::SrcSpan;
+use crate::tast::Ex;
+use crate::value::{Partial, Value};
+use std::rc::Rc;
+
+/// A compiled lambda together with the values of its free names, except for
+/// globals, which it refers to directly.
+#[derive(Debug)]
+pub struct Closure {
+    pub(crate) proto: Rc<Proto>,
+    pub(crate) captured: Vec<Value>,
+}
+
+type Result<A> = std::result::Result<A, RuntimeError>;
+
+/// Compiles top-level expressions to bytecode and runs them, with the same
+/// results and errors as `interp::Interp`. Steps are counted per instruction
+/// and the depth is the number of calls in progress, so limits are hit at
+/// different points than in the interpreter.
+#[derive(Clone, Default)]
+pub struct Vm {
+    compiler: Compiler,
+    globals: Vec<Value>,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::default()
+    }
+
+    /// Evaluates a top-level expression. Bindings define a global for
+    /// everything evaluated after them and have no value of their own.
+    pub fn eval_top(&mut self, ex: &Ex) -> Result<Option<Value>> {
+        self.eval_top_in(ex, &mut Budget::new(Limits::default()))
+    }
+
+    /// Like `eval_top`, but stops when the budget runs out.
+    pub fn eval_top_in(&mut self, ex: &Ex, budget: &mut Budget) -> Result<Option<Value>> {
+        let proto = self.compiler.compile_top(ex)?;
+        let val = Machine::new(&self.globals, budget).run(proto)?;
+        match ex {
+            Ex::Bind(b) => {
+                self.compiler.define(&b.t.name.t);
+                self.globals.push(val);
+                Ok(None)
+            }
+            _ => Ok(Some(val)),
+        }
+    }
+}
+
+/// A call in progress.
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Index of the first slot on the stack, the called function is right
+    /// below it.
+    base: usize,
+    call_site: Option<SrcSpan>,
+    /// Extra arguments of the call, which are passed on to its result.
+    pending: Vec<Value>,
+}
+
+struct Machine<'a> {
+    globals: &'a [Value],
+    budget: &'a mut Budget,
+    stack: Vec<Value>,
+    /// The frame of the top-level item comes first, a tail call replaces the
+    /// frame of its caller.
+    frames: Vec<Frame>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(globals: &'a [Value], budget: &'a mut Budget) -> Machine<'a> {
+        Machine {
+            globals,
+            budget,
+            stack: vec![],
+            frames: vec![],
+        }
+    }
+
+    fn run(mut self, proto: Rc<Proto>) -> Result<Value> {
+        let closure = Rc::new(Closure {
+            proto,
+            captured: vec![],
+        });
+        self.stack.push(Value::Compiled(closure.clone()));
+        self.enter(closure, None, vec![])?;
+
+        let result = self.exec();
+        for _ in &self.frames {
+            self.budget.leave();
+        }
+        result.map_err(|e| {
+            let trace: Vec<_> = self.frames[1..]
+                .iter()
+                .map(|f| interp::Frame {
+                    name: f.closure.proto.name.as_ref().map(|n| n.to_string()),
+                    call_site: f.call_site,
+                })
+                .collect();
+            e.with_trace(&trace)
+        })
+    }
+
+    /// Pushes a frame for the closure, whose arguments are on top of the
+    /// stack.
+    fn enter(
+        &mut self,
+        closure: Rc<Closure>,
+        call_site: Option<SrcSpan>,
+        pending: Vec<Value>,
+    ) -> Result<()> {
+        self.budget.enter(call_site)?;
+        let base = self.stack.len() - closure.proto.arity;
+        // Slots of lets are set before they are read, the value is a filler.
+        self.stack.resize(base + closure.proto.slots, Value::Int(0));
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base,
+            call_site,
+            pending,
+        });
+        Ok(())
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("No frame to run")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("No frame to run")
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    fn exec(&mut self) -> Result<Value> {
+        loop {
+            let frame = self.frame_mut();
+            let (op, span, base) = (
+                frame.closure.proto.code[frame.ip],
+                frame.closure.proto.spans[frame.ip],
+                frame.base,
+            );
+            frame.ip += 1;
+            self.budget.step(span)?;
+
+            match op {
+                Op::Const(idx) => {
+                    let val = self.frame().closure.proto.consts[idx as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::Local(slot) => {
+                    let val = self.stack[base + slot as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::SetLocal(slot) => {
+                    let val = self.pop();
+                    self.stack[base + slot as usize] = val;
+                }
+                Op::Captured(idx) => {
+                    let val = self.frame().closure.captured[idx as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::Global(g) => self.stack.push(self.globals[g as usize].clone()),
+                Op::This => self
+                    .stack
+                    .push(Value::Compiled(self.frame().closure.clone())),
+                Op::Closure(idx) => {
+                    let current = &self.frame().closure;
+                    let proto = current.proto.protos[idx as usize].clone();
+                    let captured = proto
+                        .captures
+                        .iter()
+                        .map(|c| match c {
+                            Capture::Local(slot) => self.stack[base + *slot as usize].clone(),
+                            Capture::Captured(idx) => current.captured[*idx as usize].clone(),
+                            Capture::This => Value::Compiled(current.clone()),
+                        })
+                        .collect();
+                    let closure = Closure { proto, captured };
+                    self.stack.push(Value::Compiled(Rc::new(closure)));
+                }
+                Op::Call(argc) => self.call(argc as usize, span, false)?,
+                Op::TailCall(argc) => self.call(argc as usize, span, true)?,
+                Op::Jump(target) => self.frame_mut().ip = target as usize,
+                Op::JumpIfFalse(target) => match self.pop() {
+                    Value::Bool(true) => {}
+                    Value::Bool(false) => self.frame_mut().ip = target as usize,
+                    other => {
+                        return Err(RuntimeError::new(
+                            format!("Expected a boolean condition, found: {}", other),
+                            span,
+                        ))
+                    }
+                },
+                Op::Return => {
+                    let val = self.pop();
+                    let frame = self.frames.pop().expect("No frame to return from");
+                    self.budget.leave();
+                    self.stack.truncate(frame.base - 1);
+                    if self.frames.is_empty() {
+                        return Ok(val);
+                    }
+                    if frame.pending.is_empty() {
+                        self.stack.push(val);
+                    } else {
+                        self.call_value(val, frame.pending, frame.call_site)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls the function below `argc` arguments on top of the stack. Exact
+    /// calls are done in place, everything else goes through `call_value`.
+    fn call(&mut self, argc: usize, span: Option<SrcSpan>, tail: bool) -> Result<()> {
+        let at = self.stack.len() - argc - 1;
+        match &self.stack[at] {
+            Value::Compiled(c) if c.proto.arity == argc => {
+                let closure = c.clone();
+                if tail {
+                    let frame = self.frames.last_mut().expect("No frame to replace");
+                    self.stack.drain(frame.base - 1..at);
+                    self.stack
+                        .resize(frame.base + closure.proto.slots, Value::Int(0));
+                    frame.closure = closure;
+                    frame.ip = 0;
+                    frame.call_site = span;
+                    Ok(())
+                } else {
+                    self.enter(closure, span, vec![])
+                }
+            }
+            Value::Builtin(b) if b.arity == argc => {
+                let val =
+                    (b.func)(&self.stack[at + 1..]).map_err(|msg| RuntimeError::new(msg, span))?;
+                self.stack.truncate(at);
+                self.stack.push(val);
+                Ok(())
+            }
+            _ => {
+                let args = self.stack.split_off(at + 1);
+                let func = self.pop();
+                self.call_value(func, args, span)
+            }
+        }
+    }
+
+    /// Calls a function value the way the interpreter does. Too few arguments
+    /// make a partial application, extra ones are passed on to the result of
+    /// the call.
+    fn call_value(
+        &mut self,
+        mut func: Value,
+        mut args: Vec<Value>,
+        span: Option<SrcSpan>,
+    ) -> Result<()> {
+        let mut rest = vec![];
+        loop {
+            let arity = func.arity().ok_or_else(|| {
+                RuntimeError::new(format!("Cannot apply a non-function: {}", func), span)
+            })?;
+            if args.len() < arity {
+                let partial = Value::Partial(Rc::new(Partial { func, args }));
+                self.stack.push(partial);
+                return Ok(());
+            }
+            let extra = args.split_off(arity);
+            if !extra.is_empty() {
+                rest = extra;
+            }
+
+            match func {
+                Value::Compiled(c) => {
+                    self.stack.push(Value::Compiled(c.clone()));
+                    self.stack.extend(args);
+                    return self.enter(c, span, rest);
+                }
+                Value::Builtin(b) => {
+                    let val = (b.func)(&args).map_err(|msg| RuntimeError::new(msg, span))?;
+                    if rest.is_empty() {
+                        self.stack.push(val);
+                        return Ok(());
+                    }
+                    func = val;
+                    args = std::mem::take(&mut rest);
+                }
+                Value::Partial(p) => {
+                    args = p.args.iter().cloned().chain(args).collect();
+                    func = p.func.clone();
+                }
+                Value::Closure(_) => {
+                    return Err(RuntimeError::new(
+                        format!("Cannot apply an interpreted function: {}", func),
+                        span,
+                    ))
+                }
+                Value::Int(_) | Value::Bool(_) => unreachable!("Only functions have an arity"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interp::Interp;
+    use crate::limits::Limit;
+    use crate::lower::lower_unit;
+    use crate::parser::{parse, WithCode};
+    use crate::resolve::resolve_unit;
+    use pretty_assertions::assert_eq;
+
+    /// Runs the code with both backends and returns what each printed.
+    fn run_both(code: &str) -> (Vec<String>, Vec<String>) {
+        let unit = parse(code).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        // Untyped, so that code the type checker rejects can be compared too.
+        let exs = lower_unit(&unit, &res).unwrap();
+
+        let show = |val: Result<Option<Value>>| match val {
+            Ok(val) => val.map(|v| format!("{}", v)),
+            Err(e) => Some(format!("{}", WithCode::new(code, &e))),
+        };
+        let mut interp = Interp::new();
+        let mut vm = Vm::new();
+        let mut from_interp = vec![];
+        let mut from_vm = vec![];
+        for ex in &exs {
+            from_interp.extend(show(interp.eval_top(ex)));
+            from_vm.extend(show(vm.eval_top(ex)));
+        }
+        (from_interp, from_vm)
+    }
+
+    fn assert_same(code: &str) -> Vec<String> {
+        let (from_interp, from_vm) = run_both(code);
+        assert_eq!(from_interp, from_vm);
+        from_vm
+    }
+
+    #[test]
+    fn t_vm_matches_interp() {
+        let printed = assert_same(
+            r"n = 20
+              fib n = if n < 2 then 1 else fib(n - 1) + fib(n - 2)
+              fib(n)
+              make_adder n = \x -> x + n
+              make_adder(2)(40)
+              let x = 1, f y = x + y in let x = 100 in f(1)
+              let twice f = \x -> f(f(x)), inc = \x -> x + 1 in twice(twice(inc))(0)
+              (\x y z -> x - y + z)(10)(1, 2)
+              minus(5)
+              let k x = \y -> x in k(1, 2)
+              fib
+              \x -> x
+              n == 20",
+        );
+        assert_eq!(
+            printed,
+            vec![
+                "10946",
+                "42",
+                "2",
+                "4",
+                "11",
+                "<builtin minus>(5, ...)",
+                "1",
+                "<function fib>",
+                "<lambda>",
+                "True"
+            ]
+        );
+    }
+
+    #[test]
+    fn t_vm_tail_calls() {
+        let printed = assert_same(
+            "count n acc = if n < 1 then acc else count(n - 1, acc + 1)
+             count(100000, 0)
+             let down n = if n < 1 then 0 else let m = n - 1 in down(m) in down(100000)",
+        );
+        assert_eq!(printed, vec!["100000", "0"]);
+    }
+
+    #[test]
+    fn t_vm_errors() {
+        assert_same(
+            "let check n = if n < 0 then error() else n,
+                 down n = if n < 1 then check(n - 1) else 1 + down(n - 1)
+             in down(3)",
+        );
+        assert_same("deep n = if n < 1 then error() else 1 + deep(n - 1)\ndeep(20)");
+        assert_same("f x = x / 0\nlet g h = h(1) in g(f)");
+        assert_same("1 + 2(3)");
+        assert_same("if 1 then 2 else 3");
+    }
+
+    #[test]
+    fn t_vm_deep_recursion() {
+        let deep = "deep n = if n < 1 then 0 else 1 + deep(n - 1)\n";
+        assert_eq!(assert_same(&format!("{}deep(100)", deep)), vec!["100"]);
+
+        let unit = parse(&format!("{}deep(200000)", deep)).unwrap();
+        let res = resolve_unit(&unit).unwrap();
+        let exs = lower_unit(&unit, &res).unwrap();
+        let mut interp = Interp::new();
+        let mut vm = Vm::new();
+        for ex in &exs[..1] {
+            interp.eval_top(ex).unwrap();
+            vm.eval_top(ex).unwrap();
+        }
+        // Both stop at the default depth limit, if at different points.
+        let depth = Some(Limit::Depth);
+        assert_eq!(
+            interp.eval_top(&exs[1]).unwrap_err().exceeded_limit(),
+            depth
+        );
+        assert_eq!(vm.eval_top(&exs[1]).unwrap_err().exceeded_limit(), depth);
+    }
+}